@@ -1,13 +1,22 @@
 //! Methods to connect to a WebSocket as a client.
 
 use std::{
-    io::{Read, Write},
+    io::{self, Read, Write},
+    mem::replace,
     net::{SocketAddr, TcpStream, ToSocketAddrs},
     result::Result as StdResult,
+    sync::mpsc,
+    thread,
+    time::{Duration, Instant},
 };
 
-use http::{request::Parts, HeaderName, Uri};
+#[cfg(unix)]
+use std::{os::unix::net::UnixStream, path::Path};
+
+use http::{request::Parts, HeaderMap, HeaderName, StatusCode, Uri};
 use log::*;
+#[cfg(feature = "socket2")]
+use socket2::{Domain, Protocol, Socket, Type};
 
 use crate::{
     handshake::client::{generate_key, Request, Response},
@@ -44,11 +53,211 @@ pub fn connect_with_config<Req: IntoClientRequest>(
     request: Req,
     config: Option<WebSocketConfig>,
     max_redirects: u8,
+) -> Result<(WebSocket<MaybeTlsStream<TcpStream>>, Response)> {
+    let options = ConnectOptions::default().config(config).max_redirects(max_redirects);
+    let (socket, response, _) = connect_with_options(request, options)?;
+    Ok((socket, response))
+}
+
+/// Options for [`connect_with_options`].
+#[non_exhaustive]
+#[derive(Clone, Copy, Debug)]
+pub struct ConnectOptions {
+    /// The configuration of the WebSocket, `None` for the default one.
+    pub config: Option<WebSocketConfig>,
+    /// The maximum number of redirects to follow. The default value is 3.
+    pub max_redirects: u8,
+    /// Give up connecting once the timeout has elapsed. The default value is `None`, which
+    /// waits as long as the operating system allows.
+    ///
+    /// The timeout covers the whole connection including all redirects: resolving the host
+    /// name, connecting, the TLS handshake and the WebSocket handshake. If it elapses,
+    /// [`Error::Io`] of kind [`TimedOut`](io::ErrorKind::TimedOut) is returned, also if the
+    /// server keeps sending data too slowly to finish in time.
+    ///
+    /// The handshakes are done with a non-blocking stream, the returned stream is blocking
+    /// again and has no read timeout set. Host names are resolved on a separate thread, which
+    /// keeps running if resolving times out.
+    pub timeout: Option<Duration>,
+    /// Bind the local end of the TCP connection to this address before connecting.
+    /// The default value is `None`.
+    ///
+    /// This is useful on multihomed hosts to pin outgoing traffic to a specific interface.
+    /// Use port `0` to let the operating system pick a free local port. Target addresses
+    /// of a different IP family than the local address are skipped.
+    #[cfg(feature = "socket2")]
+    pub local_addr: Option<SocketAddr>,
+}
+
+impl Default for ConnectOptions {
+    fn default() -> Self {
+        ConnectOptions {
+            config: None,
+            max_redirects: 3,
+            timeout: None,
+            #[cfg(feature = "socket2")]
+            local_addr: None,
+        }
+    }
+}
+
+impl ConnectOptions {
+    /// Set [`Self::config`].
+    pub fn config(mut self, config: Option<WebSocketConfig>) -> Self {
+        self.config = config;
+        self
+    }
+
+    /// Set [`Self::max_redirects`].
+    pub fn max_redirects(mut self, max_redirects: u8) -> Self {
+        self.max_redirects = max_redirects;
+        self
+    }
+
+    /// Set [`Self::timeout`].
+    pub fn timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Set [`Self::local_addr`].
+    #[cfg(feature = "socket2")]
+    pub fn local_addr(mut self, local_addr: Option<SocketAddr>) -> Self {
+        self.local_addr = local_addr;
+        self
+    }
+}
+
+/// Details about how a connection was established, see [`connect_with_options`].
+#[non_exhaustive]
+#[derive(Clone, Debug)]
+pub struct ConnectInfo {
+    /// The redirects followed, each as the URI which was redirected away from together with
+    /// the status code of the redirect response, in the order they were followed.
+    pub redirects: Vec<(Uri, StatusCode)>,
+    /// Time spent in the phases of the final connection.
+    pub timing: ConnectTiming,
+    /// The mode of the stream, which tells whether the connection is actually encrypted.
+    /// It may differ from the scheme of the request if a redirect was followed,
+    /// e.g. from `wss://` to `ws://`.
+    pub mode: Mode,
+}
+
+/// Time spent in the phases of establishing a connection, see [`ConnectInfo::timing`].
+#[non_exhaustive]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ConnectTiming {
+    /// Time spent resolving the host name.
+    pub dns: Duration,
+    /// Time spent establishing the TCP connection.
+    pub tcp_connect: Duration,
+    /// Time spent in the TLS handshake, zero for `ws://` URLs.
+    pub tls: Duration,
+    /// Time spent in the WebSocket handshake, from sending the request until the
+    /// response was received.
+    pub handshake: Duration,
+}
+
+impl ConnectTiming {
+    /// The total time spent connecting.
+    pub fn total(&self) -> Duration {
+        self.dns + self.tcp_connect + self.tls + self.handshake
+    }
+}
+
+/// Connect to the given WebSocket in blocking mode with the given `options`, also returning
+/// details about how the connection was established.
+///
+/// Apart from that it works exactly like [`connect_with_config`].
+pub fn connect_with_options<Req: IntoClientRequest>(
+    request: Req,
+    options: ConnectOptions,
+) -> Result<(WebSocket<MaybeTlsStream<TcpStream>>, Response, ConnectInfo)> {
+    let deadline = Deadline(options.timeout.map(|timeout| Instant::now() + timeout));
+    let mut redirects = Vec::new();
+    let mut timing = ConnectTiming::default();
+    let (socket, response) = connect_impl(request, options, deadline, &mut redirects, &mut timing)?;
+    let mode = socket.get_ref().mode();
+    Ok((socket, response, ConnectInfo { redirects, timing, mode }))
+}
+
+/// Point in time to give up connecting, if any.
+#[derive(Clone, Copy, Debug)]
+struct Deadline(Option<Instant>);
+
+impl Deadline {
+    /// Get the time left until the deadline, failing once it has passed.
+    fn remaining(&self) -> io::Result<Option<Duration>> {
+        match self.0.map(|deadline| deadline.saturating_duration_since(Instant::now())) {
+            Some(remaining) if remaining.is_zero() => Err(timed_out()),
+            remaining => Ok(remaining),
+        }
+    }
+
+    /// Wait until the non-blocking `stream` has data to read, failing once the deadline has
+    /// passed.
+    ///
+    /// Handshakes only block on writing if the send buffer is full, which doesn't happen
+    /// with the little data sent while connecting, so waiting for the stream to be readable
+    /// is enough to make progress.
+    fn wait_readable(&self, stream: &TcpStream) -> Result<()> {
+        let timeout = match self.remaining()? {
+            Some(timeout) => timeout,
+            None => return Err(Error::Io(io::ErrorKind::WouldBlock.into())),
+        };
+        stream.set_nonblocking(false)?;
+        stream.set_read_timeout(Some(timeout))?;
+        let peeked = stream.peek(&mut [0]);
+        stream.set_nonblocking(true)?;
+        match peeked {
+            Ok(_) => Ok(()),
+            Err(e) if matches!(e.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut) => {
+                self.remaining()?;
+                Ok(())
+            }
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Resolve `host`, giving up once the deadline has passed.
+    fn resolve(&self, host: &str, port: u16) -> Result<Vec<SocketAddr>> {
+        let timeout = match self.remaining()? {
+            Some(timeout) => timeout,
+            None => return Ok((host, port).to_socket_addrs()?.collect()),
+        };
+        // Resolving can't be interrupted, so do it on a thread which may outlive the deadline.
+        let (sender, receiver) = mpsc::channel();
+        let host = host.to_owned();
+        thread::Builder::new().spawn(move || {
+            let addrs = (host.as_str(), port).to_socket_addrs().map(Iterator::collect);
+            let _ = sender.send(addrs);
+        })?;
+        match receiver.recv_timeout(timeout) {
+            Ok(addrs) => Ok(addrs?),
+            Err(_) => Err(Error::Io(timed_out())),
+        }
+    }
+}
+
+fn timed_out() -> io::Error {
+    io::Error::new(io::ErrorKind::TimedOut, "connection timed out")
+}
+
+fn connect_impl<Req: IntoClientRequest>(
+    request: Req,
+    options: ConnectOptions,
+    deadline: Deadline,
+    redirects: &mut Vec<(Uri, StatusCode)>,
+    timing: &mut ConnectTiming,
 ) -> Result<(WebSocket<MaybeTlsStream<TcpStream>>, Response)> {
     fn try_client_handshake(
         request: Request,
-        config: Option<WebSocketConfig>,
+        options: ConnectOptions,
+        deadline: Deadline,
+        timing: &mut ConnectTiming,
     ) -> Result<(WebSocket<MaybeTlsStream<TcpStream>>, Response)> {
+        *timing = ConnectTiming::default();
+
         let uri = request.uri();
         let mode = uri_mode(uri)?;
 
@@ -63,19 +272,65 @@ pub fn connect_with_config<Req: IntoClientRequest>(
             Mode::Plain => 80,
             Mode::Tls => 443,
         });
-        let addrs = (host, port).to_socket_addrs()?;
-        let mut stream = connect_to_some(addrs.as_slice(), request.uri())?;
+        let start = Instant::now();
+        let addrs = deadline.resolve(host, port)?;
+        timing.dns = start.elapsed();
+
+        let start = Instant::now();
+        let mut stream = connect_to_some(&addrs, request.uri(), options, deadline)?;
+        timing.tcp_connect = start.elapsed();
         NoDelay::set_nodelay(&mut stream, true)?;
+        // With a deadline, the handshakes return control whenever they would block, so every
+        // wait for data from the server is bounded by the time left.
+        if deadline.0.is_some() {
+            stream.set_nonblocking(true)?;
+        }
+        #[cfg_attr(not(any(feature = "native-tls", feature = "__rustls-tls")), allow(unused))]
+        let wait = move |stream: &TcpStream| deadline.wait_readable(stream);
 
+        let start = Instant::now();
         #[cfg(not(any(feature = "native-tls", feature = "__rustls-tls")))]
-        let client = client_with_config(request, MaybeTlsStream::Plain(stream), config);
+        let stream = MaybeTlsStream::Plain(stream);
         #[cfg(any(feature = "native-tls", feature = "__rustls-tls"))]
-        let client = crate::tls::client_tls_with_config(request, stream, config, None);
+        let stream = {
+            let mut stream = crate::tls::wrap_client_stream(&request, stream, None, wait)?;
+            crate::tls::complete_tls_handshake(&mut stream, wait)?;
+            stream
+        };
+        if mode == Mode::Tls {
+            timing.tls = start.elapsed();
+        }
+
+        let start = Instant::now();
+        let mut result = client_with_config(request, stream, options.config);
+        let client = loop {
+            match result {
+                Ok(client) => break Ok(client),
+                Err(HandshakeError::Failure(f)) => break Err(f),
+                Err(HandshakeError::Interrupted(mid)) => {
+                    if let Err(e) = wait(mid.get_ref().get_ref().tcp_ref()) {
+                        break Err(e);
+                    }
+                    result = mid.handshake();
+                }
+            }
+        };
+        timing.handshake = start.elapsed();
+        client
+    }
 
-        client.map_err(|e| match e {
-            HandshakeError::Failure(f) => f,
-            HandshakeError::Interrupted(_) => panic!("Bug: blocking handshake not blocked"),
-        })
+    fn try_client_handshake_until_deadline(
+        request: Request,
+        options: ConnectOptions,
+        deadline: Deadline,
+        timing: &mut ConnectTiming,
+    ) -> Result<(WebSocket<MaybeTlsStream<TcpStream>>, Response)> {
+        let (socket, response) = try_client_handshake(request, options, deadline, timing)?;
+        if deadline.0.is_some() {
+            socket.get_ref().tcp_ref().set_nonblocking(false)?;
+            socket.get_ref().set_read_timeout(None)?;
+        }
+        Ok((socket, response))
     }
 
     fn create_request(parts: &Parts, uri: &Uri) -> Request {
@@ -88,13 +343,16 @@ pub fn connect_with_config<Req: IntoClientRequest>(
     let (parts, _) = request.into_client_request()?.into_parts();
     let mut uri = parts.uri.clone();
 
-    for attempt in 0..=max_redirects {
+    for attempt in 0..=options.max_redirects {
         let request = create_request(&parts, &uri);
 
-        match try_client_handshake(request, config) {
-            Err(Error::Http(res)) if res.status().is_redirection() && attempt < max_redirects => {
+        match try_client_handshake_until_deadline(request, options, deadline, timing) {
+            Err(Error::Http(res))
+                if res.status().is_redirection() && attempt < options.max_redirects =>
+            {
                 if let Some(location) = res.headers().get("Location") {
-                    uri = location.to_str()?.parse::<Uri>()?;
+                    let location = location.to_str()?.parse::<Uri>()?;
+                    redirects.push((replace(&mut uri, location), res.status()));
                     debug!("Redirecting to {uri:?}");
                     continue;
                 } else {
@@ -127,16 +385,79 @@ pub fn connect<Req: IntoClientRequest>(
     connect_with_config(request, None, 3)
 }
 
-fn connect_to_some(addrs: &[SocketAddr], uri: &Uri) -> Result<TcpStream> {
+fn connect_to_some(
+    addrs: &[SocketAddr],
+    uri: &Uri,
+    #[cfg_attr(not(feature = "socket2"), allow(unused))] options: ConnectOptions,
+    deadline: Deadline,
+) -> Result<TcpStream> {
     for addr in addrs {
         debug!("Trying to contact {uri} at {addr}...");
-        if let Ok(stream) = TcpStream::connect(addr) {
+        let timeout = deadline.remaining()?;
+        #[cfg(feature = "socket2")]
+        if let Some(local_addr) = options.local_addr {
+            if let Ok(stream) = connect_from(local_addr, addr, timeout) {
+                return Ok(stream);
+            }
+            continue;
+        }
+        let stream = match timeout {
+            Some(timeout) => TcpStream::connect_timeout(addr, timeout),
+            None => TcpStream::connect(addr),
+        };
+        if let Ok(stream) = stream {
             return Ok(stream);
         }
     }
     Err(Error::Url(UrlError::UnableToConnect(uri.to_string())))
 }
 
+/// Connect to the given WebSocket over a Unix domain socket in blocking mode.
+///
+/// The connection is made to the socket at `path`, while `request` is only used for the
+/// handshake. Its URI must still use the `ws` scheme, and its host becomes the `Host` header,
+/// e.g. `ws://localhost/socket`.
+///
+/// ```no_run
+/// # use tungstenite::client::connect_unix;
+/// let (socket, _) = connect_unix("/run/service.sock", "ws://localhost/socket", None).unwrap();
+/// ```
+#[cfg(unix)]
+pub fn connect_unix<P, Req>(
+    path: P,
+    request: Req,
+    config: Option<WebSocketConfig>,
+) -> Result<(WebSocket<UnixStream>, Response)>
+where
+    P: AsRef<Path>,
+    Req: IntoClientRequest,
+{
+    let stream = UnixStream::connect(path)?;
+    client_with_config(request, stream, config).map_err(|e| match e {
+        HandshakeError::Failure(f) => f,
+        HandshakeError::Interrupted(_) => panic!("Bug: blocking handshake not blocked"),
+    })
+}
+
+/// Connect to `addr` from a socket bound to `local_addr`.
+#[cfg(feature = "socket2")]
+fn connect_from(
+    local_addr: SocketAddr,
+    addr: &SocketAddr,
+    timeout: Option<Duration>,
+) -> io::Result<TcpStream> {
+    if local_addr.is_ipv4() != addr.is_ipv4() {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "IP family mismatch"));
+    }
+    let socket = Socket::new(Domain::for_address(*addr), Type::STREAM, Some(Protocol::TCP))?;
+    socket.bind(&local_addr.into())?;
+    match timeout {
+        Some(timeout) => socket.connect_timeout(&(*addr).into(), timeout)?,
+        None => socket.connect(&(*addr).into())?,
+    }
+    Ok(socket.into())
+}
+
 /// Get the mode of the given URL.
 ///
 /// This function may be used to ease the creation of custom TLS streams
@@ -292,17 +613,47 @@ impl IntoClientRequest for httparse::Request<'_, '_> {
 #[derive(Debug, Clone)]
 pub struct ClientRequestBuilder {
     uri: Uri,
+    /// Headers adopted from an existing [`Request`]
+    base_headers: Option<HeaderMap>,
     /// Additional [`Request`] handshake headers
     additional_headers: Vec<(String, String)>,
     /// Handsake subprotocols
     subprotocols: Vec<String>,
+    /// Explicit `Host` header, overriding the one derived from the URI
+    host: Option<String>,
+    /// Explicit `Sec-WebSocket-Version` header, overriding the standard version 13
+    version: Option<String>,
 }
 
 impl ClientRequestBuilder {
     /// Initializes an empty request builder
     #[must_use]
     pub const fn new(uri: Uri) -> Self {
-        Self { uri, additional_headers: Vec::new(), subprotocols: Vec::new() }
+        Self {
+            uri,
+            base_headers: None,
+            additional_headers: Vec::new(),
+            subprotocols: Vec::new(),
+            host: None,
+            version: None,
+        }
+    }
+
+    /// Initializes a request builder from an existing request, adopting its URI and headers.
+    ///
+    /// Headers of the request replace the generated handshake headers of the same name,
+    /// so e.g. an existing `Sec-WebSocket-Key` is kept.
+    #[must_use]
+    pub fn from_request(request: Request) -> Self {
+        let (parts, ()) = request.into_parts();
+        Self {
+            uri: parts.uri,
+            base_headers: Some(parts.headers),
+            additional_headers: Vec::new(),
+            subprotocols: Vec::new(),
+            host: None,
+            version: None,
+        }
     }
 
     /// Adds (`key`, `value`) as an additional header to the handshake request
@@ -316,6 +667,10 @@ impl ClientRequestBuilder {
     }
 
     /// Adds `protocol` to the handshake request subprotocols (`Sec-WebSocket-Protocol`)
+    ///
+    /// Subprotocols are offered in the order they are added, which conventionally expresses
+    /// the client's preference. The server picks at most one of them, which is available with
+    /// [`WebSocket::selected_subprotocol`] after the handshake.
     pub fn with_sub_protocol<P>(mut self, protocol: P) -> Self
     where
         P: Into<String>,
@@ -323,12 +678,47 @@ impl ClientRequestBuilder {
         self.subprotocols.push(protocol.into());
         self
     }
+
+    /// Sets the `Host` header of the handshake request.
+    ///
+    /// By default the `Host` header is derived from the URI authority. Setting it explicitly
+    /// allows it to differ from the server which is actually connected to, which is still
+    /// taken from the URI. This is useful e.g. behind reverse proxies that route by `Host`,
+    /// or when connecting to a server by its IP address.
+    pub fn with_host<H>(mut self, host: H) -> Self
+    where
+        H: Into<String>,
+    {
+        self.host = Some(host.into());
+        self
+    }
+
+    /// Sets the `Sec-WebSocket-Version` header of the handshake request.
+    ///
+    /// **Warning:** only version `13` (the default) is compliant with RFC 6455. Other versions
+    /// refer to drafts of the protocol and should only be used to talk to legacy servers which
+    /// don't accept version 13 but are otherwise compatible with it.
+    pub fn with_websocket_version<V>(mut self, version: V) -> Self
+    where
+        V: Into<String>,
+    {
+        self.version = Some(version.into());
+        self
+    }
 }
 
 impl IntoClientRequest for ClientRequestBuilder {
     fn into_client_request(self) -> Result<Request> {
         let mut request = self.uri.into_client_request()?;
         let headers = request.headers_mut();
+        if let Some(base_headers) = self.base_headers {
+            for name in base_headers.keys() {
+                headers.remove(name);
+            }
+            for (name, value) in &base_headers {
+                headers.append(name, value.clone());
+            }
+        }
         for (k, v) in self.additional_headers {
             let key = HeaderName::try_from(k)?;
             let value = v.parse()?;
@@ -338,6 +728,77 @@ impl IntoClientRequest for ClientRequestBuilder {
             let protocols = self.subprotocols.join(", ").parse()?;
             headers.append("Sec-WebSocket-Protocol", protocols);
         }
+        if let Some(host) = self.host {
+            headers.insert("Host", host.parse()?);
+        }
+        if let Some(version) = self.version {
+            headers.insert("Sec-WebSocket-Version", version.parse()?);
+        }
         Ok(request)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{ClientRequestBuilder, IntoClientRequest};
+
+    #[test]
+    fn request_builder_subprotocol_order() {
+        let request = ClientRequestBuilder::new("ws://localhost/socket".parse().unwrap())
+            .with_sub_protocol("chat")
+            .with_sub_protocol("superchat")
+            .with_sub_protocol("json")
+            .into_client_request()
+            .unwrap();
+        assert_eq!(request.headers()["Sec-WebSocket-Protocol"], "chat, superchat, json");
+    }
+
+    #[test]
+    fn request_builder_from_request() {
+        let request = http::Request::builder()
+            .uri("ws://localhost/socket")
+            .header("Authorization", "Bearer token")
+            .header("Sec-WebSocket-Key", "dGhlIHNhbXBsZSBub25jZQ==")
+            .body(())
+            .unwrap();
+
+        let request = ClientRequestBuilder::from_request(request)
+            .with_header("X-Extra", "1")
+            .with_sub_protocol("chat")
+            .into_client_request()
+            .unwrap();
+
+        assert_eq!(request.uri(), "ws://localhost/socket");
+        let headers = request.headers();
+        assert_eq!(headers["Host"], "localhost");
+        assert_eq!(headers["Upgrade"], "websocket");
+        assert_eq!(headers["Authorization"], "Bearer token");
+        assert_eq!(headers.get_all("Sec-WebSocket-Key").iter().count(), 1);
+        assert_eq!(headers["Sec-WebSocket-Key"], "dGhlIHNhbXBsZSBub25jZQ==");
+        assert_eq!(headers["X-Extra"], "1");
+        assert_eq!(headers["Sec-WebSocket-Protocol"], "chat");
+    }
+
+    #[test]
+    fn request_builder_with_host() {
+        let request = ClientRequestBuilder::new("ws://127.0.0.1:8080/socket".parse().unwrap())
+            .with_host("example.com")
+            .into_client_request()
+            .unwrap();
+
+        assert_eq!(request.uri(), "ws://127.0.0.1:8080/socket");
+        assert_eq!(request.headers().get_all("Host").iter().count(), 1);
+        assert_eq!(request.headers()["Host"], "example.com");
+    }
+
+    #[test]
+    fn request_builder_with_websocket_version() {
+        let request = ClientRequestBuilder::new("ws://localhost/socket".parse().unwrap())
+            .with_websocket_version("8")
+            .into_client_request()
+            .unwrap();
+
+        assert_eq!(request.headers().get_all("Sec-WebSocket-Version").iter().count(), 1);
+        assert_eq!(request.headers()["Sec-WebSocket-Version"], "8");
+    }
+}
@@ -1,8 +1,11 @@
 //! Error handling.
 
-use std::{io, result, str, string};
+use std::{io, result, str, string, time::Duration};
 
-use crate::protocol::{frame::coding::Data, Message};
+use crate::protocol::{
+    frame::coding::{CloseCode, Data},
+    Message,
+};
 #[cfg(feature = "handshake")]
 use http::{header::HeaderName, Response};
 use thiserror::Error;
@@ -75,6 +78,54 @@ pub enum Error {
     HttpFormat(#[from] http::Error),
 }
 
+impl Error {
+    /// Get the delay requested by the server in the `Retry-After` header of a rejected
+    /// handshake, e.g. one answered with `429 Too Many Requests` or `503 Service Unavailable`.
+    ///
+    /// Only the delay-seconds form of the header is supported; `None` is returned if the
+    /// header is missing, is an HTTP date or if this is not an [`Error::Http`].
+    pub fn retry_after(&self) -> Option<Duration> {
+        match self {
+            #[cfg(feature = "handshake")]
+            Error::Http(response) => response
+                .headers()
+                .get(http::header::RETRY_AFTER)?
+                .to_str()
+                .ok()?
+                .trim()
+                .parse()
+                .ok()
+                .map(Duration::from_secs),
+            _ => None,
+        }
+    }
+
+    /// Get the close code corresponding to an abnormal closure of the connection.
+    ///
+    /// Returns [`CloseCode::Abnormal`] (1006) if the connection was closed without a close
+    /// frame, i.e. for [`ProtocolError::ResetWithoutClosingHandshake`] and for I/O errors
+    /// caused by the connection being reset, aborted or ended unexpectedly. Returns `None`
+    /// for any other error.
+    pub fn abnormal_close_code(&self) -> Option<CloseCode> {
+        match self {
+            Error::Protocol(ProtocolError::ResetWithoutClosingHandshake) => {
+                Some(CloseCode::Abnormal)
+            }
+            Error::Io(e)
+                if matches!(
+                    e.kind(),
+                    io::ErrorKind::ConnectionReset
+                        | io::ErrorKind::ConnectionAborted
+                        | io::ErrorKind::UnexpectedEof
+                ) =>
+            {
+                Some(CloseCode::Abnormal)
+            }
+            _ => None,
+        }
+    }
+}
+
 impl From<str::Utf8Error> for Error {
     fn from(_: str::Utf8Error) -> Self {
         Error::Utf8
@@ -176,6 +227,11 @@ pub enum ProtocolError {
     /// Wrong HTTP version used (the WebSocket protocol requires version 1.1 or higher).
     #[error("HTTP version must be 1.1 or higher")]
     WrongHttpVersion,
+    /// The server responded using HTTP/2, which can't be used for the handshake.
+    ///
+    /// This usually means the server negotiated `h2` via ALPN or is misconfigured.
+    #[error("Server responded with HTTP/2, but the handshake requires HTTP/1.1")]
+    Http2Response,
     /// Missing `Connection: upgrade` HTTP header.
     #[error("No \"Connection: upgrade\" header")]
     MissingConnectionUpgradeHeader,
@@ -188,12 +244,19 @@ pub enum ProtocolError {
     /// Missing `Sec-WebSocket-Key` HTTP header.
     #[error("No \"Sec-WebSocket-Key\" header")]
     MissingSecWebSocketKey,
+    /// The `Sec-WebSocket-Key` HTTP header is not a base64-encoded 16 byte value.
+    #[error("Invalid \"Sec-WebSocket-Key\" header")]
+    InvalidSecWebSocketKey,
     /// The `Sec-WebSocket-Accept` header is either not present or does not specify the correct key value.
     #[error("Key mismatch in \"Sec-WebSocket-Accept\" header")]
     SecWebSocketAcceptKeyMismatch,
     /// The `Sec-WebSocket-Protocol` header was invalid
     #[error("SubProtocol error: {0}")]
     SecWebSocketSubProtocolError(SubProtocolError),
+    /// The server accepted an extension in the `Sec-WebSocket-Extensions` header which the
+    /// client didn't request.
+    #[error("Server sent an unrequested extension: {0}")]
+    UnrequestedExtension(String),
     /// Garbage data encountered after client request.
     #[error("Junk after client request")]
     JunkAfterRequest,
@@ -212,11 +275,17 @@ pub enum ProtocolError {
     #[cfg(feature = "handshake")]
     HttparseError(#[from] httparse::Error),
     /// Not allowed to send after having sent a closing frame.
+    ///
+    /// Contains the message which was not sent.
     #[error("Sending after closing is not allowed")]
-    SendAfterClosing,
+    SendAfterClosing(Message),
     /// Remote sent data after sending a closing frame.
     #[error("Remote sent after having closed")]
     ReceivedAfterClosing,
+    /// Remote sent more data than allowed after we initiated the closing handshake
+    /// (see [`WebSocketConfig::max_data_after_close`](crate::protocol::WebSocketConfig::max_data_after_close)).
+    #[error("Remote sent too much data after we sent a close frame")]
+    TooMuchDataAfterClose,
     /// Reserved bits in frame header are non-zero.
     #[error("Reserved bits are non-zero")]
     NonZeroReservedBits,
@@ -226,6 +295,14 @@ pub enum ProtocolError {
     /// The client must close the connection when a masked frame is received.
     #[error("Received a masked frame from server")]
     MaskedFrameFromServer,
+    /// Receiving a frame took longer than allowed by the configuration
+    /// (see [`WebSocketConfig::max_frame_read_time`](crate::protocol::WebSocketConfig::max_frame_read_time)).
+    #[error("Frame not received in time")]
+    FrameReadTimeout,
+    /// The frame header wasn't complete after the number of reads allowed by the configuration
+    /// (see [`WebSocketConfig::max_frame_header_reads`](crate::protocol::WebSocketConfig::max_frame_header_reads)).
+    #[error("Frame header not received in time")]
+    FrameHeaderReadLimit,
     /// Control frames must not be fragmented.
     #[error("Fragmented control frame")]
     FragmentedControlFrame,
@@ -241,10 +318,21 @@ pub enum ProtocolError {
     /// Received a continue frame despite there being nothing to continue.
     #[error("Continue frame but nothing to continue")]
     UnexpectedContinueFrame,
-    /// Received data while waiting for more fragments.
-    #[error("While waiting for more fragments received: {0}")]
+    /// Received a new data message while waiting for more fragments of the previous one.
+    /// Only one fragmented message may be in flight at a time, but control frames may be
+    /// interleaved with its fragments.
+    #[error("While waiting for more fragments received a new {0} frame")]
     ExpectedFragment(Data),
+    /// Tried to send a new data message while sending a fragmented message started with
+    /// `begin_message` is still in progress.
+    #[error("Sending a fragmented message is in progress")]
+    MessageInProgress,
+    /// Tried to send a fragment without starting a fragmented message with `begin_message`.
+    #[error("No fragmented message has been started")]
+    MessageNotStarted,
     /// Connection closed without performing the closing handshake.
+    ///
+    /// This corresponds to the close code 1006, see [`Error::abnormal_close_code`].
     #[error("Connection reset without closing handshake")]
     ResetWithoutClosingHandshake,
     /// Encountered an invalid opcode.
@@ -253,6 +341,9 @@ pub enum ProtocolError {
     /// The payload for the closing frame is invalid.
     #[error("Invalid close sequence")]
     InvalidCloseSequence,
+    /// The close code is not allowed to be sent in a close frame.
+    #[error("Invalid close code: {0}")]
+    InvalidCloseCode(u16),
 }
 
 /// Indicates the specific type/cause of URL error.
@@ -28,6 +28,37 @@ pub type Request = HttpRequest<()>;
 /// Client response type.
 pub type Response = HttpResponse<Option<Vec<u8>>>;
 
+/// The raw bytes of the server's handshake response head (status line and headers), exactly as
+/// received, before any parsing.
+///
+/// It is attached to the extensions of the [`Response`] read by the client if
+/// [`WebSocketConfig::keep_raw_handshake`] is enabled, which is useful to get at data that
+/// parsing into [`http`] types might normalize away.
+///
+/// ```no_run
+/// # use tungstenite::{client::connect_with_config, handshake::client::RawResponseHeaders};
+/// # use tungstenite::protocol::WebSocketConfig;
+/// let config = WebSocketConfig::default().keep_raw_handshake(true);
+/// let (_, response) =
+///     connect_with_config("ws://localhost:3012/socket", Some(config), 3).unwrap();
+/// let raw = response.extensions().get::<RawResponseHeaders>().unwrap();
+/// println!("{}", String::from_utf8_lossy(raw.as_bytes()));
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RawResponseHeaders(Vec<u8>);
+
+impl RawResponseHeaders {
+    /// Returns the raw bytes of the response head, including the terminating empty line.
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.0
+    }
+
+    /// Consumes this and returns the raw bytes of the response head.
+    pub fn into_bytes(self) -> Vec<u8> {
+        self.0
+    }
+}
+
 /// Client handshake role.
 #[derive(Debug)]
 pub struct ClientHandshake<S> {
@@ -55,6 +86,7 @@ impl<S: Read + Write> ClientHandshake<S> {
         let _ = crate::client::uri_mode(request.uri())?;
 
         let subprotocols = extract_subprotocols_from_request(&request)?;
+        let extensions = extension_names(request.headers())?;
 
         // Convert and verify the `http::Request` and turn it into the request as per RFC.
         // Also extract the key from it (it must be present in a correct request).
@@ -65,7 +97,7 @@ impl<S: Read + Write> ClientHandshake<S> {
         let client = {
             let accept_key = derive_accept_key(key.as_ref());
             ClientHandshake {
-                verify_data: VerifyData { accept_key, subprotocols },
+                verify_data: VerifyData { accept_key, subprotocols, extensions },
                 config,
                 _marker: PhantomData,
             }
@@ -86,21 +118,46 @@ impl<S: Read + Write> HandshakeRole for ClientHandshake<S> {
     ) -> Result<ProcessingResult<Self::InternalStream, Self::FinalResult>> {
         Ok(match finish {
             StageResult::DoneWriting(stream) => {
-                ProcessingResult::Continue(HandshakeMachine::start_read(stream))
+                let keep_head = self.config.map_or(false, |c| c.keep_raw_handshake);
+                ProcessingResult::Continue(
+                    HandshakeMachine::start_read(stream).keep_head(keep_head),
+                )
             }
-            StageResult::DoneReading { stream, result, tail } => {
+            StageResult::DoneReading { stream, mut result, tail, head } => {
+                if let Some(head) = head {
+                    result.extensions_mut().insert(RawResponseHeaders(head));
+                }
                 let result = match self.verify_data.verify_response(result) {
                     Ok(r) => r,
                     Err(Error::Http(mut e)) => {
+                        // Don't keep an arbitrary amount of data sent by a rogue server.
+                        let mut tail = tail;
+                        tail.truncate(
+                            self.config.unwrap_or_default().max_handshake_error_body_size,
+                        );
                         *e.body_mut() = Some(tail);
                         return Err(Error::Http(e));
                     }
                     Err(e) => return Err(e),
                 };
 
-                debug!("Client handshake done.");
-                let websocket =
+                let mut websocket =
                     WebSocket::from_partially_read(stream, tail, Role::Client, self.config);
+                info!(
+                    "Client handshake done: subprotocol {:?}, {:?}",
+                    result.headers().get("Sec-WebSocket-Protocol"),
+                    websocket.effective_limits()
+                );
+                websocket.set_selected_subprotocol(
+                    result
+                        .headers()
+                        .get("Sec-WebSocket-Protocol")
+                        .and_then(|h| h.to_str().ok())
+                        .map(str::to_owned),
+                );
+                if self.config.map_or(false, |c| c.store_handshake_response) {
+                    websocket.set_handshake_response(result.clone());
+                }
                 ProcessingResult::Done((websocket, result))
             }
         })
@@ -144,28 +201,27 @@ pub fn generate_request(mut request: Request) -> Result<(Vec<u8>, String)> {
     // See similar problem in `hyper`: https://github.com/hyperium/hyper/issues/1492
     let headers = request.headers_mut();
     for &header in &WEBSOCKET_HEADERS {
-        let value = headers.remove(header).ok_or_else(|| {
+        let invalid_header = || {
             Error::Protocol(ProtocolError::InvalidHeader(
                 HeaderName::from_bytes(header.as_bytes()).unwrap(),
             ))
-        })?;
+        };
+
+        // The headers required by the WebSocket protocol must be present exactly once.
+        // Note that `remove()` drops all values of the header, so duplicates must be
+        // detected beforehand.
+        if headers.get_all(header).iter().count() > 1 {
+            return Err(invalid_header());
+        }
+        let value = headers.remove(header).ok_or_else(invalid_header)?;
         write!(req, "{header}: {value}\r\n", header = header, value = value.to_str()?).unwrap();
     }
 
-    // Now we must ensure that the headers that we've written once are not anymore present in the map.
-    // If they do, then the request is invalid (some headers are duplicated there for some reason).
-    let insensitive: Vec<String> =
-        WEBSOCKET_HEADERS.iter().map(|h| h.to_ascii_lowercase()).collect();
+    // All other headers are written as they are, in order. They may legitimately be repeated
+    // (e.g. multiple `Cookie` headers), in which case every value is written.
     for (k, v) in headers {
         let mut name = k.as_str();
 
-        // We have already written the necessary headers once (above) and removed them from the map.
-        // If we encounter them again, then the request is considered invalid and error is returned.
-        // Note that we can't use `.contains()`, since `&str` does not coerce to `&String` in Rust.
-        if insensitive.iter().any(|x| x == name) {
-            return Err(Error::Protocol(ProtocolError::InvalidHeader(k.clone())));
-        }
-
         // Relates to the issue of some servers treating headers in a case-sensitive way, please see:
         // https://github.com/snapview/tungstenite-rs/pull/119 (original fix of the problem)
         if name == "sec-websocket-protocol" {
@@ -192,6 +248,21 @@ fn extract_subprotocols_from_request(request: &Request) -> Result<Option<Vec<Str
     }
 }
 
+/// Get the names of the extensions listed in the `Sec-WebSocket-Extensions` header(s),
+/// ignoring their parameters.
+fn extension_names(headers: &HeaderMap) -> Result<Vec<String>> {
+    let mut names = Vec::new();
+    for value in headers.get_all("Sec-WebSocket-Extensions") {
+        for extension in value.to_str()?.split(',') {
+            let name = extension.split(';').next().unwrap_or_default().trim();
+            if !name.is_empty() {
+                names.push(name.to_owned());
+            }
+        }
+    }
+    Ok(names)
+}
+
 /// Information for handshake verification.
 #[derive(Debug)]
 struct VerifyData {
@@ -200,6 +271,9 @@ struct VerifyData {
 
     /// Accepted subprotocols
     subprotocols: Option<Vec<String>>,
+
+    /// Names of the extensions requested by the client.
+    extensions: Vec<String>,
 }
 
 impl VerifyData {
@@ -248,7 +322,12 @@ impl VerifyData {
         // that was not present in the client's handshake (the server has
         // indicated an extension not requested by the client), the client
         // MUST _Fail the WebSocket Connection_. (RFC 6455)
-        // TODO
+        // Extension names are tokens, which are compared case-insensitively.
+        if let Some(name) = extension_names(headers)?.into_iter().find(|name| {
+            !self.extensions.iter().any(|requested| requested.eq_ignore_ascii_case(name))
+        }) {
+            return Err(Error::Protocol(ProtocolError::UnrequestedExtension(name)));
+        }
 
         // 6.  If the response includes a |Sec-WebSocket-Protocol| header field
         // and this header field indicates the use of a subprotocol that was
@@ -285,13 +364,43 @@ impl TryParse for Response {
     fn try_parse(buf: &[u8]) -> Result<Option<(usize, Self)>> {
         let mut hbuffer = [httparse::EMPTY_HEADER; MAX_HEADERS];
         let mut req = httparse::Response::new(&mut hbuffer);
-        Ok(match req.parse(buf)? {
+        let status = match req.parse(buf) {
+            Ok(status) => status,
+            Err(err) => {
+                return match is_http2(buf) {
+                    Some(true) => Err(Error::Protocol(ProtocolError::Http2Response)),
+                    Some(false) => Err(err.into()),
+                    // Wait for more data to tell.
+                    None => Ok(None),
+                };
+            }
+        };
+        Ok(match status {
             Status::Partial => None,
             Status::Complete(size) => Some((size, Response::from_httparse(req)?)),
         })
     }
 }
 
+/// Check whether a response which failed to parse as HTTP/1.x is HTTP/2.
+///
+/// Returns `None` if more data is needed to tell.
+fn is_http2(buf: &[u8]) -> Option<bool> {
+    if buf.starts_with(b"HTTP/2") {
+        return Some(true);
+    }
+    // An HTTP/2 server starts the connection with a SETTINGS frame (type 0x4) on stream 0.
+    const FRAME_HEADER_LEN: usize = 9;
+    let header = &buf[..buf.len().min(FRAME_HEADER_LEN)];
+    let settings_frame =
+        header.get(3).map_or(true, |&ty| ty == 0x04) && header.iter().skip(5).all(|&b| b == 0);
+    if settings_frame && header.len() < FRAME_HEADER_LEN {
+        None
+    } else {
+        Some(settings_frame)
+    }
+}
+
 impl<'h, 'b: 'h> FromHttparse<httparse::Response<'h, 'b>> for Response {
     fn from_httparse(raw: httparse::Response<'h, 'b>) -> Result<Self> {
         if raw.version.expect("Bug: no HTTP version") < /*1.*/1 {
@@ -321,8 +430,16 @@ pub fn generate_key() -> String {
 
 #[cfg(test)]
 mod tests {
-    use super::{super::machine::TryParse, generate_key, generate_request, Response};
-    use crate::client::IntoClientRequest;
+    use super::{
+        super::{machine::TryParse, HandshakeError},
+        generate_key, generate_request, ClientHandshake, RawResponseHeaders, Response, VerifyData,
+    };
+    use crate::{
+        client::IntoClientRequest,
+        error::{Error, ProtocolError},
+        protocol::WebSocketConfig,
+    };
+    use std::io::{self, Cursor, Read, Write};
 
     #[test]
     fn random_keys() {
@@ -386,9 +503,172 @@ mod tests {
         assert_eq!(resp.headers().get("Content-Type").unwrap(), &b"text/html"[..],);
     }
 
+    #[test]
+    fn http2_response() {
+        // SETTINGS frame with a single setting
+        const SETTINGS: &[u8] = &[0, 0, 6, 4, 0, 0, 0, 0, 0, 0, 3, 0, 0, 0, 100];
+        for data in [SETTINGS, b"HTTP/2 200\r\n\r\n"] {
+            assert!(matches!(
+                Response::try_parse(data),
+                Err(Error::Protocol(ProtocolError::Http2Response))
+            ));
+        }
+    }
+
+    #[cfg(feature = "sans-io")]
+    #[test]
+    fn http2_response_in_pieces() {
+        use super::super::machine::SansIoStream;
+
+        const SETTINGS: &[u8] = &[0, 0, 6, 4, 0, 0, 0, 0, 0, 0, 3, 0, 0, 0, 100];
+        let request = "ws://localhost/socket".into_client_request().unwrap();
+        let mut client = ClientHandshake::start(SansIoStream::new(), request, None).unwrap();
+        for piece in SETTINGS[..8].chunks(3) {
+            client = match client.handshake() {
+                Err(HandshakeError::Interrupted(mid)) => mid,
+                _ => panic!("handshake should wait for more data"),
+            };
+            client.feed(piece);
+        }
+        let mut client = match client.handshake() {
+            Err(HandshakeError::Interrupted(mid)) => mid,
+            _ => panic!("handshake should wait for more data"),
+        };
+        client.feed(&SETTINGS[8..]);
+        assert!(matches!(
+            client.handshake(),
+            Err(HandshakeError::Failure(Error::Protocol(ProtocolError::Http2Response)))
+        ));
+
+        // Data which can't be a SETTINGS frame is rejected as soon as that is clear.
+        assert!(matches!(
+            Response::try_parse(&[0, 0, 6, 1]),
+            Err(Error::Protocol(ProtocolError::HttparseError(_)))
+        ));
+    }
+
+    #[test]
+    fn response_raw_headers() {
+        const DATA: &[u8] = b"HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\n\
+            Connection: Upgrade\r\nSec-WebSocket-Accept: s3pPLMBiTxaQ9kYGzzhZRbK+xOo=\r\n\r\n";
+        let handshake = |config: WebSocketConfig| {
+            let mut request = "ws://localhost/socket".into_client_request().unwrap();
+            request
+                .headers_mut()
+                .insert("Sec-WebSocket-Key", "dGhlIHNhbXBsZSBub25jZQ==".parse().unwrap());
+            let stream = MockServer(Cursor::new(DATA.to_vec()));
+            let (_, response) =
+                ClientHandshake::start(stream, request, Some(config)).unwrap().handshake().unwrap();
+            response
+        };
+
+        let response = handshake(WebSocketConfig::default());
+        assert!(response.extensions().get::<RawResponseHeaders>().is_none());
+
+        let response = handshake(WebSocketConfig::default().keep_raw_handshake(true));
+        let raw = response.extensions().get::<RawResponseHeaders>().unwrap();
+        assert_eq!(raw.as_bytes(), DATA);
+    }
+
+    /// Stream replying with fixed data and discarding everything written.
+    #[derive(Debug)]
+    struct MockServer(Cursor<Vec<u8>>);
+
+    impl Read for MockServer {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.0.read(buf)
+        }
+    }
+
+    impl Write for MockServer {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            Ok(buf.len())
+        }
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn error_response_body_limit() {
+        let mut data = b"HTTP/1.1 404 Not Found\r\nContent-Length: 100\r\n\r\n".to_vec();
+        data.extend_from_slice(&[b'x'; 100]);
+        let request = "ws://localhost/socket".into_client_request().unwrap();
+        let config = WebSocketConfig::default().max_handshake_error_body_size(10);
+
+        let err = ClientHandshake::start(MockServer(Cursor::new(data)), request, Some(config))
+            .unwrap()
+            .handshake()
+            .unwrap_err();
+        match err {
+            HandshakeError::Failure(Error::Http(response)) => {
+                assert_eq!(response.status(), http::StatusCode::NOT_FOUND);
+                assert_eq!(response.body().as_deref(), Some(&[b'x'; 10][..]));
+            }
+            err => panic!("unexpected error {err:?}"),
+        }
+    }
+
     #[test]
     fn invalid_custom_request() {
         let request = http::Request::builder().method("GET").body(()).unwrap();
         assert!(generate_request(request).is_err());
     }
+
+    #[test]
+    fn request_formatting_with_duplicate_headers() {
+        let mut request = "ws://localhost/getCaseCount".into_client_request().unwrap();
+        request.headers_mut().append("Cookie", "a=1".parse().unwrap());
+        request.headers_mut().append("Cookie", "b=2".parse().unwrap());
+        let (request, key) = generate_request(request).unwrap();
+        let mut correct = construct_expected("localhost", &key);
+        correct.truncate(correct.len() - 2);
+        correct.extend_from_slice(b"cookie: a=1\r\ncookie: b=2\r\n\r\n");
+        assert_eq!(&request[..], &correct[..]);
+    }
+
+    #[test]
+    fn unrequested_extension() {
+        let response = |extensions: &str| {
+            http::Response::builder()
+                .status(http::StatusCode::SWITCHING_PROTOCOLS)
+                .header("Upgrade", "websocket")
+                .header("Connection", "Upgrade")
+                .header("Sec-WebSocket-Accept", "key")
+                .header("Sec-WebSocket-Extensions", extensions)
+                .body(None)
+                .unwrap()
+        };
+        let verify_data = VerifyData {
+            accept_key: "key".into(),
+            subprotocols: None,
+            extensions: vec!["permessage-deflate".into(), "x-custom".into()],
+        };
+
+        assert!(verify_data.verify_response(response("x-custom")).is_ok());
+        assert!(verify_data.verify_response(response("Permessage-Deflate")).is_ok());
+        assert!(verify_data
+            .verify_response(response("permessage-deflate; client_max_window_bits=10"))
+            .is_ok());
+        assert!(matches!(
+            verify_data.verify_response(response("x-custom, x-other; param")),
+            Err(Error::Protocol(ProtocolError::UnrequestedExtension(name))) if name == "x-other"
+        ));
+
+        let verify_data = VerifyData { extensions: vec![], ..verify_data };
+        assert!(matches!(
+            verify_data.verify_response(response("permessage-deflate")),
+            Err(Error::Protocol(ProtocolError::UnrequestedExtension(_)))
+        ));
+    }
+
+    #[test]
+    fn request_with_duplicate_required_header() {
+        let mut request = "ws://localhost/getCaseCount".into_client_request().unwrap();
+        request.headers_mut().append("Host", "example.com".parse().unwrap());
+        assert!(matches!(
+            generate_request(request),
+            Err(Error::Protocol(ProtocolError::InvalidHeader(h))) if h == "host"
+        ));
+    }
 }
@@ -15,16 +15,41 @@ use crate::{
 pub struct HandshakeMachine<Stream> {
     stream: Stream,
     state: HandshakeState,
+    keep_head: bool,
 }
 
 impl<Stream> HandshakeMachine<Stream> {
     /// Start reading data from the peer.
     pub fn start_read(stream: Stream) -> Self {
-        Self { stream, state: HandshakeState::Reading(ReadBuffer::new(), AttackCheck::new()) }
+        Self {
+            stream,
+            state: HandshakeState::Reading(ReadBuffer::new(), AttackCheck::new()),
+            keep_head: false,
+        }
+    }
+    /// Start reading data from the peer, with data which has already been read from the stream.
+    ///
+    /// The data is parsed before reading from the stream, so e.g. the buffer of a `BufReader`
+    /// wrapping the stream can be handed over instead of buffering the handshake twice.
+    pub fn start_read_with_data(stream: Stream, data: Vec<u8>) -> Result<Self> {
+        let mut attack_check = AttackCheck::new();
+        attack_check.check_incoming_packet_size(data.len())?;
+        let buf = ReadBuffer::from_partially_read(data);
+        Ok(Self { stream, state: HandshakeState::Parsing(buf, attack_check), keep_head: false })
     }
     /// Start writing data to the peer.
     pub fn start_write<D: Into<Vec<u8>>>(stream: Stream, data: D) -> Self {
-        HandshakeMachine { stream, state: HandshakeState::Writing(Cursor::new(data.into())) }
+        HandshakeMachine {
+            stream,
+            state: HandshakeState::Writing(Cursor::new(data.into())),
+            keep_head: false,
+        }
+    }
+    /// Keep a copy of the raw head of the object read, returned by
+    /// [`StageResult::DoneReading`].
+    pub fn keep_head(mut self, keep_head: bool) -> Self {
+        self.keep_head = keep_head;
+        self
     }
     /// Returns a shared reference to the inner stream.
     pub fn get_ref(&self) -> &Stream {
@@ -41,28 +66,16 @@ impl<Stream: Read + Write> HandshakeMachine<Stream> {
     pub fn single_round<Obj: TryParse>(mut self) -> Result<RoundResult<Obj, Stream>> {
         trace!("Doing handshake round.");
         match self.state {
+            HandshakeState::Parsing(buf, attack_check) => {
+                Self::try_finish_reading(self.stream, buf, attack_check, self.keep_head)
+            }
             HandshakeState::Reading(mut buf, mut attack_check) => {
                 let read = buf.read_from(&mut self.stream).no_block()?;
                 match read {
                     Some(0) => Err(Error::Protocol(ProtocolError::HandshakeIncomplete)),
                     Some(count) => {
                         attack_check.check_incoming_packet_size(count)?;
-                        // TODO: this is slow for big headers with too many small packets.
-                        // The parser has to be reworked in order to work on streams instead
-                        // of buffers.
-                        Ok(if let Some((size, obj)) = Obj::try_parse(Buf::chunk(&buf))? {
-                            buf.advance(size);
-                            RoundResult::StageFinished(StageResult::DoneReading {
-                                result: obj,
-                                stream: self.stream,
-                                tail: buf.into_vec(),
-                            })
-                        } else {
-                            RoundResult::Incomplete(HandshakeMachine {
-                                state: HandshakeState::Reading(buf, attack_check),
-                                ..self
-                            })
-                        })
+                        Self::try_finish_reading(self.stream, buf, attack_check, self.keep_head)
                     }
                     None => Ok(RoundResult::WouldBlock(HandshakeMachine {
                         state: HandshakeState::Reading(buf, attack_check),
@@ -104,6 +117,93 @@ impl<Stream: Read + Write> HandshakeMachine<Stream> {
     }
 }
 
+/// An in-memory stream to drive a handshake without doing any I/O, e.g. over a
+/// message-oriented transport.
+///
+/// Data received from the peer is passed in with [`feed`](Self::feed), data to send to the
+/// peer is taken with [`take_output`](Self::take_output). Reading fails with `WouldBlock` once
+/// all fed data has been consumed, so [`MidHandshake::handshake`] returns
+/// [`HandshakeError::Interrupted`] until the handshake is done.
+///
+/// [`MidHandshake::handshake`]: super::MidHandshake::handshake
+/// [`HandshakeError::Interrupted`]: super::HandshakeError::Interrupted
+#[cfg(feature = "sans-io")]
+#[derive(Debug, Default)]
+pub struct SansIoStream {
+    input: std::collections::VecDeque<u8>,
+    output: Vec<u8>,
+}
+
+#[cfg(feature = "sans-io")]
+impl SansIoStream {
+    /// Create an empty stream.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add data received from the peer.
+    pub fn feed(&mut self, data: &[u8]) {
+        self.input.extend(data);
+    }
+
+    /// Take the data to be sent to the peer.
+    pub fn take_output(&mut self) -> Vec<u8> {
+        std::mem::take(&mut self.output)
+    }
+}
+
+#[cfg(feature = "sans-io")]
+impl Read for SansIoStream {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        if self.input.is_empty() && !buf.is_empty() {
+            return Err(std::io::ErrorKind::WouldBlock.into());
+        }
+        self.input.read(buf)
+    }
+}
+
+#[cfg(feature = "sans-io")]
+impl Write for SansIoStream {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.output.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+impl<Stream> HandshakeMachine<Stream> {
+    /// Finish the reading stage if the buffer holds a complete object, continue reading otherwise.
+    fn try_finish_reading<Obj: TryParse>(
+        stream: Stream,
+        mut buf: ReadBuffer,
+        attack_check: AttackCheck,
+        keep_head: bool,
+    ) -> Result<RoundResult<Obj, Stream>> {
+        // TODO: this is slow for big headers with too many small packets.
+        // The parser has to be reworked in order to work on streams instead
+        // of buffers.
+        Ok(if let Some((size, obj)) = Obj::try_parse(Buf::chunk(&buf))? {
+            let head = keep_head.then(|| Buf::chunk(&buf)[..size].to_vec());
+            buf.advance(size);
+            RoundResult::StageFinished(StageResult::DoneReading {
+                result: obj,
+                stream,
+                tail: buf.into_vec(),
+                head,
+            })
+        } else {
+            RoundResult::Incomplete(HandshakeMachine {
+                stream,
+                state: HandshakeState::Reading(buf, attack_check),
+                keep_head,
+            })
+        })
+    }
+}
+
 /// The result of the round.
 #[derive(Debug)]
 pub enum RoundResult<Obj, Stream> {
@@ -118,9 +218,10 @@ pub enum RoundResult<Obj, Stream> {
 /// The result of the stage.
 #[derive(Debug)]
 pub enum StageResult<Obj, Stream> {
-    /// Reading round finished.
+    /// Reading round finished. `head` holds the raw bytes `result` was parsed from if
+    /// requested with [`HandshakeMachine::keep_head`].
     #[allow(missing_docs)]
-    DoneReading { result: Obj, stream: Stream, tail: Vec<u8> },
+    DoneReading { result: Obj, stream: Stream, tail: Vec<u8>, head: Option<Vec<u8>> },
     /// Writing round finished.
     DoneWriting(Stream),
 }
@@ -134,6 +235,8 @@ pub trait TryParse: Sized {
 /// The handshake state.
 #[derive(Debug)]
 enum HandshakeState {
+    /// Parsing data already read from the peer before reading more.
+    Parsing(ReadBuffer, AttackCheck),
     /// Reading data from the peer.
     Reading(ReadBuffer, AttackCheck),
     /// Sending data to the peer.
@@ -9,10 +9,13 @@ use std::{
     error::Error as ErrorTrait,
     fmt,
     io::{Read, Write},
+    time::Duration,
 };
 
 use sha1::{Digest, Sha1};
 
+#[cfg(feature = "sans-io")]
+use self::machine::SansIoStream;
 use self::machine::{HandshakeMachine, RoundResult, StageResult, TryParse};
 use crate::error::Error;
 
@@ -52,6 +55,19 @@ impl<Role: HandshakeRole> MidHandshake<Role> {
     }
 }
 
+#[cfg(feature = "sans-io")]
+impl<Role: HandshakeRole<InternalStream = SansIoStream>> MidHandshake<Role> {
+    /// Add data received from the peer, see [`SansIoStream::feed`].
+    pub fn feed(&mut self, data: &[u8]) {
+        self.machine.get_mut().feed(data)
+    }
+
+    /// Take the data to be sent to the peer, see [`SansIoStream::take_output`].
+    pub fn take_output(&mut self) -> Vec<u8> {
+        self.machine.get_mut().take_output()
+    }
+}
+
 /// A handshake result.
 pub enum HandshakeError<Role: HandshakeRole> {
     /// Handshake was interrupted (would block).
@@ -60,6 +76,17 @@ pub enum HandshakeError<Role: HandshakeRole> {
     Failure(Error),
 }
 
+impl<Role: HandshakeRole> HandshakeError<Role> {
+    /// Get the delay requested by the server in the `Retry-After` header if the handshake
+    /// was rejected, see [`Error::retry_after`].
+    pub fn retry_after(&self) -> Option<Duration> {
+        match self {
+            HandshakeError::Interrupted(_) => None,
+            HandshakeError::Failure(e) => e.retry_after(),
+        }
+    }
+}
+
 impl<Role: HandshakeRole> fmt::Debug for HandshakeError<Role> {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match *self {
@@ -125,11 +152,60 @@ pub fn derive_accept_key(request_key: &[u8]) -> String {
 
 #[cfg(test)]
 mod tests {
-    use super::derive_accept_key;
+    use std::time::Duration;
+
+    use http::Response;
+
+    use super::{client::ClientHandshake, derive_accept_key, HandshakeError};
+    use crate::error::Error;
 
     #[test]
     fn key_conversion() {
         // example from RFC 6455
         assert_eq!(derive_accept_key(b"dGhlIHNhbXBsZSBub25jZQ=="), "s3pPLMBiTxaQ9kYGzzhZRbK+xOo=");
     }
+
+    #[test]
+    fn retry_after() {
+        let rejected = |retry_after: &str| -> HandshakeError<ClientHandshake<std::net::TcpStream>> {
+            let response = Response::builder()
+                .status(503)
+                .header("Retry-After", retry_after)
+                .body(None)
+                .unwrap();
+            HandshakeError::Failure(Error::Http(response))
+        };
+        assert_eq!(rejected("120").retry_after(), Some(Duration::from_secs(120)));
+        assert_eq!(rejected("Wed, 21 Oct 2015 07:28:00 GMT").retry_after(), None);
+        assert_eq!(Error::ConnectionClosed.retry_after(), None);
+    }
+
+    #[cfg(feature = "sans-io")]
+    #[test]
+    fn sans_io() {
+        use super::{
+            machine::SansIoStream,
+            server::{NoCallback, ServerHandshake},
+        };
+        use crate::{client::IntoClientRequest, protocol::Message};
+
+        let request = "ws://localhost/socket".into_client_request().unwrap();
+        let client = ClientHandshake::start(SansIoStream::new(), request, None).unwrap();
+        let mut server = ServerHandshake::start(SansIoStream::new(), NoCallback, None);
+
+        let mut client = match client.handshake() {
+            Err(HandshakeError::Interrupted(mid)) => mid,
+            _ => panic!("client handshake should wait for the response"),
+        };
+        server.feed(&client.take_output());
+        let mut server_socket = server.handshake().unwrap();
+
+        client.feed(&server_socket.get_mut().take_output());
+        let (mut client_socket, response) = client.handshake().unwrap();
+        assert_eq!(response.status(), 101);
+
+        client_socket.send(Message::text("hi")).unwrap();
+        server_socket.get_mut().feed(&client_socket.get_mut().take_output());
+        assert_eq!(server_socket.read().unwrap(), Message::text("hi"));
+    }
 }
@@ -1,13 +1,16 @@
 //! Server handshake machine.
 
 use std::{
-    io::{self, Read, Write},
+    io::{self, BufReader, Read, Write},
     marker::PhantomData,
+    mem,
     result::Result as StdResult,
 };
 
 use http::{
-    response::Builder, HeaderMap, Request as HttpRequest, Response as HttpResponse, StatusCode,
+    header::{self, HeaderName, HeaderValue},
+    response::Builder,
+    HeaderMap, Request as HttpRequest, Response as HttpResponse, StatusCode,
 };
 use httparse::Status;
 use log::*;
@@ -32,7 +35,41 @@ pub type Response = HttpResponse<()>;
 /// Server error response type.
 pub type ErrorResponse = HttpResponse<Option<String>>;
 
-fn create_parts<T>(request: &HttpRequest<T>) -> Result<Builder> {
+/// The raw request head as received from the client, e.g. for audit logging.
+///
+/// This is available as an extension of the [`Request`] passed to the [`Callback`] if
+/// [`WebSocketConfig::keep_raw_handshake`] is enabled:
+///
+/// ```
+/// # use tungstenite::handshake::server::{ErrorResponse, RawRequest, Request, Response};
+/// let callback = |request: &Request, response: Response| -> Result<Response, ErrorResponse> {
+///     if let Some(raw) = request.extensions().get::<RawRequest>() {
+///         log::info!("Request: {}", String::from_utf8_lossy(raw.as_bytes()));
+///     }
+///     Ok(response)
+/// };
+/// # let _ = callback(&Request::default(), Response::default());
+/// ```
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RawRequest(Vec<u8>);
+
+impl RawRequest {
+    /// Returns the raw bytes of the request head, including the terminating empty line.
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.0
+    }
+
+    /// Consumes this and returns the raw bytes of the request head.
+    pub fn into_bytes(self) -> Vec<u8> {
+        self.0
+    }
+}
+
+/// Check whether the request is a valid WebSocket upgrade request, without creating a response.
+///
+/// This allows e.g. routers to cheaply detect WebSocket requests. The same checks are performed
+/// by [`create_response`].
+pub fn validate_request<T>(request: &HttpRequest<T>) -> Result<()> {
     if request.method() != http::Method::GET {
         return Err(Error::Protocol(ProtocolError::WrongHttpMethod));
     }
@@ -65,17 +102,67 @@ fn create_parts<T>(request: &HttpRequest<T>) -> Result<Builder> {
         return Err(Error::Protocol(ProtocolError::MissingSecWebSocketVersionHeader));
     }
 
+    match request.headers().get("Sec-WebSocket-Key") {
+        None => return Err(Error::Protocol(ProtocolError::MissingSecWebSocketKey)),
+        // The key must be a base64-encoded 16 byte value. (RFC 6455)
+        Some(key)
+            if data_encoding::BASE64.decode(key.as_bytes()).map_or(true, |k| k.len() != 16) =>
+        {
+            return Err(Error::Protocol(ProtocolError::InvalidSecWebSocketKey));
+        }
+        Some(_) => {}
+    }
+
+    Ok(())
+}
+
+/// Check whether the raw bytes received on a connection are a WebSocket upgrade request.
+///
+/// Returns `None` if the request headers are incomplete, so more data has to be received, and
+/// `Some(false)` for anything which is not a valid upgrade request, including data which isn't
+/// HTTP at all. This allows servers to serve both plain HTTP and WebSockets on the same port.
+/// The bytes can be obtained without consuming them with e.g.
+/// [`TcpStream::peek`](std::net::TcpStream::peek), so the stream can still be passed to
+/// [`accept`](crate::accept) afterwards.
+pub fn is_websocket_upgrade(bytes: &[u8]) -> Option<bool> {
+    match Request::try_parse(bytes) {
+        Ok(None) => None,
+        Ok(Some((_, request))) => Some(validate_request(&request).is_ok()),
+        Err(_) => Some(false),
+    }
+}
+
+/// Compute the headers a server has to send in its `101 Switching Protocols` response.
+///
+/// This is meant for frameworks which build the upgrade response themselves: the returned
+/// `Connection`, `Upgrade` and `Sec-WebSocket-Accept` headers can be merged into their own
+/// response. No extensions are negotiated, so no `Sec-WebSocket-Extensions` header is returned.
+///
+/// Returns an error if the request is not a valid WebSocket upgrade request, see
+/// [`validate_request`].
+pub fn compute_accept_headers<T>(
+    request: &HttpRequest<T>,
+) -> Result<Vec<(HeaderName, HeaderValue)>> {
+    validate_request(request)?;
+
     let key = request
         .headers()
         .get("Sec-WebSocket-Key")
         .ok_or(Error::Protocol(ProtocolError::MissingSecWebSocketKey))?;
 
-    let builder = Response::builder()
-        .status(StatusCode::SWITCHING_PROTOCOLS)
-        .version(request.version())
-        .header("Connection", "Upgrade")
-        .header("Upgrade", "websocket")
-        .header("Sec-WebSocket-Accept", derive_accept_key(key.as_bytes()));
+    Ok(vec![
+        (header::CONNECTION, HeaderValue::from_static("Upgrade")),
+        (header::UPGRADE, HeaderValue::from_static("websocket")),
+        (header::SEC_WEBSOCKET_ACCEPT, HeaderValue::from_str(&derive_accept_key(key.as_bytes()))?),
+    ])
+}
+
+fn create_parts<T>(request: &HttpRequest<T>) -> Result<Builder> {
+    let mut builder =
+        Response::builder().status(StatusCode::SWITCHING_PROTOCOLS).version(request.version());
+    for (name, value) in compute_accept_headers(request)? {
+        builder = builder.header(name, value);
+    }
 
     Ok(builder)
 }
@@ -93,6 +180,23 @@ pub fn create_response_with_body<T1, T2>(
     Ok(create_parts(request)?.body(generate_body())?)
 }
 
+/// Get the subprotocols offered by the client in the `Sec-WebSocket-Protocol` header(s).
+///
+/// Clients may send multiple `Sec-WebSocket-Protocol` headers, each with a comma-separated
+/// list of subprotocols. These are merged into a single list in the order the client offered
+/// them, with empty entries and duplicates removed.
+pub fn offered_subprotocols<T>(request: &HttpRequest<T>) -> Vec<String> {
+    let mut protocols: Vec<String> = Vec::new();
+    let values = request.headers().get_all("Sec-WebSocket-Protocol").iter();
+    for protocol in values.filter_map(|h| h.to_str().ok()).flat_map(|h| h.split(',')) {
+        let protocol = protocol.trim();
+        if !protocol.is_empty() && !protocols.iter().any(|p| p == protocol) {
+            protocols.push(protocol.to_owned());
+        }
+    }
+    protocols
+}
+
 /// Write `response` to the stream `w`.
 pub fn write_response<T>(mut w: impl io::Write, response: &HttpResponse<T>) -> Result<()> {
     writeln!(
@@ -190,6 +294,36 @@ impl Callback for NoCallback {
     }
 }
 
+/// Callback accepting only clients that offer a specific subprotocol.
+///
+/// The subprotocol is selected in the `Sec-WebSocket-Protocol` response header if the client
+/// offered it, otherwise the request is rejected with `400 Bad Request`. See
+/// [`accept_with_subprotocol`](crate::accept_with_subprotocol).
+#[derive(Clone, Debug)]
+pub struct RequireSubprotocol(pub String);
+
+impl Callback for RequireSubprotocol {
+    fn on_request(
+        self,
+        request: &Request,
+        mut response: Response,
+    ) -> StdResult<Response, ErrorResponse> {
+        let offered = offered_subprotocols(request).contains(&self.0);
+        match HeaderValue::from_str(&self.0) {
+            Ok(protocol) if offered => {
+                response.headers_mut().insert("Sec-WebSocket-Protocol", protocol);
+                Ok(response)
+            }
+            _ => {
+                let mut error_response =
+                    ErrorResponse::new(Some(format!("Subprotocol {:?} not offered", self.0)));
+                *error_response.status_mut() = StatusCode::BAD_REQUEST;
+                Err(error_response)
+            }
+        }
+    }
+}
+
 /// Server handshake role.
 #[allow(missing_copy_implementations)]
 #[derive(Debug)]
@@ -202,6 +336,10 @@ pub struct ServerHandshake<S, C> {
     config: Option<WebSocketConfig>,
     /// Error code/flag. If set, an error will be returned after sending response to the client.
     error_response: Option<ErrorResponse>,
+    /// Subprotocol selected in the response, for logging.
+    subprotocol: Option<String>,
+    /// Data received after the request, see [`WebSocketConfig::allow_request_tail`].
+    tail: Vec<u8>,
     /// Internal stream type.
     _marker: PhantomData<S>,
 }
@@ -213,16 +351,46 @@ impl<S: Read + Write, C: Callback> ServerHandshake<S, C> {
     /// upon join based on the incoming headers.
     pub fn start(stream: S, callback: C, config: Option<WebSocketConfig>) -> MidHandshake<Self> {
         trace!("Server handshake initiated.");
+        let keep_head = config.map_or(false, |c| c.keep_raw_handshake);
         MidHandshake {
-            machine: HandshakeMachine::start_read(stream),
+            machine: HandshakeMachine::start_read(stream).keep_head(keep_head),
             role: ServerHandshake {
                 callback: Some(callback),
                 config,
                 error_response: None,
+                subprotocol: None,
+                tail: Vec::new(),
                 _marker: PhantomData,
             },
         }
     }
+
+    /// Start server handshake on a stream wrapped in a [`BufReader`].
+    ///
+    /// The data buffered by the reader is handed over to the handshake instead of being
+    /// buffered twice, so this works like [`start`](Self::start) on the unwrapped stream.
+    pub fn start_buffered(
+        reader: BufReader<S>,
+        callback: C,
+        config: Option<WebSocketConfig>,
+    ) -> Result<MidHandshake<Self>> {
+        trace!("Server handshake initiated with buffered data.");
+        let data = reader.buffer().to_vec();
+        let keep_head = config.map_or(false, |c| c.keep_raw_handshake);
+        let machine =
+            HandshakeMachine::start_read_with_data(reader.into_inner(), data)?.keep_head(keep_head);
+        Ok(MidHandshake {
+            machine,
+            role: ServerHandshake {
+                callback: Some(callback),
+                config,
+                error_response: None,
+                subprotocol: None,
+                tail: Vec::new(),
+                _marker: PhantomData,
+            },
+        })
+    }
 }
 
 impl<S: Read + Write, C: Callback> HandshakeRole for ServerHandshake<S, C> {
@@ -235,9 +403,15 @@ impl<S: Read + Write, C: Callback> HandshakeRole for ServerHandshake<S, C> {
         finish: StageResult<Self::IncomingData, Self::InternalStream>,
     ) -> Result<ProcessingResult<Self::InternalStream, Self::FinalResult>> {
         Ok(match finish {
-            StageResult::DoneReading { stream, result, tail } => {
+            StageResult::DoneReading { stream, mut result, tail, head } => {
+                if let Some(head) = head {
+                    result.extensions_mut().insert(RawRequest(head));
+                }
                 if !tail.is_empty() {
-                    return Err(Error::Protocol(ProtocolError::JunkAfterRequest));
+                    if !self.config.map_or(false, |c| c.allow_request_tail) {
+                        return Err(Error::Protocol(ProtocolError::JunkAfterRequest));
+                    }
+                    self.tail = tail;
                 }
 
                 let response = create_response(&result)?;
@@ -249,6 +423,11 @@ impl<S: Read + Write, C: Callback> HandshakeRole for ServerHandshake<S, C> {
 
                 match callback_result {
                     Ok(response) => {
+                        self.subprotocol = response
+                            .headers()
+                            .get("Sec-WebSocket-Protocol")
+                            .and_then(|h| h.to_str().ok())
+                            .map(str::to_owned);
                         let mut output = vec![];
                         write_response(&mut output, &response)?;
                         ProcessingResult::Continue(HandshakeMachine::start_write(stream, output))
@@ -282,8 +461,15 @@ impl<S: Read + Write, C: Callback> HandshakeRole for ServerHandshake<S, C> {
                     let body = body.map(|b| b.as_bytes().to_vec());
                     return Err(Error::Http(http::Response::from_parts(parts, body)));
                 } else {
-                    debug!("Server handshake done.");
-                    let websocket = WebSocket::from_raw_socket(stream, Role::Server, self.config);
+                    let tail = mem::take(&mut self.tail);
+                    let mut websocket =
+                        WebSocket::from_partially_read(stream, tail, Role::Server, self.config);
+                    websocket.set_selected_subprotocol(self.subprotocol.take());
+                    info!(
+                        "Server handshake done: subprotocol {:?}, {:?}",
+                        websocket.selected_subprotocol(),
+                        websocket.effective_limits()
+                    );
                     ProcessingResult::Done(websocket)
                 }
             }
@@ -293,7 +479,37 @@ impl<S: Read + Write, C: Callback> HandshakeRole for ServerHandshake<S, C> {
 
 #[cfg(test)]
 mod tests {
-    use super::{super::machine::TryParse, create_response, Request};
+    use super::{
+        super::machine::TryParse, compute_accept_headers, create_response, is_websocket_upgrade,
+        offered_subprotocols, validate_request, NoCallback, RawRequest, Request,
+        RequireSubprotocol, Response, ServerHandshake,
+    };
+    use crate::{
+        error::{Error, ProtocolError},
+        protocol::{Message, WebSocketConfig},
+        HandshakeError,
+    };
+    use std::io::{self, BufRead, BufReader, Cursor, Read, Write};
+
+    struct Stream {
+        incoming: Cursor<Vec<u8>>,
+        written: Vec<u8>,
+    }
+
+    impl Read for Stream {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.incoming.read(buf)
+        }
+    }
+
+    impl Write for Stream {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.written.write(buf)
+        }
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
 
     #[test]
     fn request_parsing() {
@@ -303,6 +519,32 @@ mod tests {
         assert_eq!(req.headers().get("Host").unwrap(), &b"foo.com"[..]);
     }
 
+    #[test]
+    fn raw_request() {
+        const DATA: &[u8] = b"\
+            GET /script.ws HTTP/1.1\r\n\
+            Host: foo.com\r\n\
+            Connection: upgrade\r\n\
+            Upgrade: websocket\r\n\
+            Sec-WebSocket-Version: 13\r\n\
+            Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\
+            \r\n";
+        let handshake = |config: WebSocketConfig| {
+            let stream = Stream { incoming: Cursor::new(DATA.to_vec()), written: vec![] };
+            let mut raw = None;
+            let callback = |request: &Request, response: Response| {
+                raw = request.extensions().get::<RawRequest>().cloned();
+                Ok(response)
+            };
+            ServerHandshake::start(stream, callback, Some(config)).handshake().unwrap();
+            raw
+        };
+
+        assert_eq!(handshake(WebSocketConfig::default()), None);
+        let raw = handshake(WebSocketConfig::default().keep_raw_handshake(true)).unwrap();
+        assert_eq!(raw.as_bytes(), DATA);
+    }
+
     #[test]
     fn request_replying() {
         const DATA: &[u8] = b"\
@@ -321,4 +563,175 @@ mod tests {
             b"s3pPLMBiTxaQ9kYGzzhZRbK+xOo=".as_ref()
         );
     }
+
+    #[test]
+    fn websocket_upgrade_detection() {
+        const DATA: &[u8] = b"\
+            GET /script.ws HTTP/1.1\r\n\
+            Host: foo.com\r\n\
+            Connection: upgrade\r\n\
+            Upgrade: websocket\r\n\
+            Sec-WebSocket-Version: 13\r\n\
+            Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\
+            \r\n";
+        assert_eq!(is_websocket_upgrade(DATA), Some(true));
+        assert_eq!(is_websocket_upgrade(&DATA[..40]), None);
+        assert_eq!(is_websocket_upgrade(b"GET / HTTP/1.1\r\nHost: foo.com\r\n\r\n"), Some(false));
+        assert_eq!(is_websocket_upgrade(b"POST / HTTP/1.1\r\n\r\n"), Some(false));
+        assert_eq!(is_websocket_upgrade(b"\x16\x03\x01\x02\x00"), Some(false));
+    }
+
+    #[test]
+    fn accept_headers() {
+        const DATA: &[u8] = b"\
+            GET /script.ws HTTP/1.1\r\n\
+            Host: foo.com\r\n\
+            Connection: upgrade\r\n\
+            Upgrade: websocket\r\n\
+            Sec-WebSocket-Version: 13\r\n\
+            Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\
+            \r\n";
+        let (_, req) = Request::try_parse(DATA).unwrap().unwrap();
+        let headers = compute_accept_headers(&req).unwrap();
+        let headers: Vec<_> =
+            headers.iter().map(|(k, v)| (k.as_str(), v.to_str().unwrap())).collect();
+        assert_eq!(
+            headers,
+            [
+                ("connection", "Upgrade"),
+                ("upgrade", "websocket"),
+                ("sec-websocket-accept", "s3pPLMBiTxaQ9kYGzzhZRbK+xOo="),
+            ]
+        );
+
+        let (_, req) =
+            Request::try_parse(b"GET / HTTP/1.1\r\nHost: foo.com\r\n\r\n").unwrap().unwrap();
+        assert!(compute_accept_headers(&req).is_err());
+    }
+
+    #[test]
+    fn subprotocols_offered() {
+        const DATA: &[u8] = b"\
+            GET /script.ws HTTP/1.1\r\n\
+            Host: foo.com\r\n\
+            Sec-WebSocket-Protocol: chat, superchat\r\n\
+            Sec-WebSocket-Protocol: json,chat,\r\n\
+            \r\n";
+        let (_, req) = Request::try_parse(DATA).unwrap().unwrap();
+        assert_eq!(offered_subprotocols(&req), ["chat", "superchat", "json"]);
+
+        let (_, req) = Request::try_parse(b"GET / HTTP/1.1\r\n\r\n").unwrap().unwrap();
+        assert!(offered_subprotocols(&req).is_empty());
+    }
+
+    #[test]
+    fn request_validation() {
+        const DATA: &[u8] = b"\
+            GET /script.ws HTTP/1.1\r\n\
+            Host: foo.com\r\n\
+            Connection: keep-alive, Upgrade\r\n\
+            Upgrade: websocket\r\n\
+            Sec-WebSocket-Version: 13\r\n\
+            Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\
+            \r\n";
+        let (_, req) = Request::try_parse(DATA).unwrap().unwrap();
+        assert!(validate_request(&req).is_ok());
+
+        let (_, req) =
+            Request::try_parse(b"GET / HTTP/1.1\r\nHost: foo.com\r\n\r\n").unwrap().unwrap();
+        assert!(matches!(
+            validate_request(&req),
+            Err(Error::Protocol(ProtocolError::MissingConnectionUpgradeHeader))
+        ));
+
+        for key in ["dGhlIHNhbXBsZQ==", "not base64", "dGhlIHNhbXBsZSBub25jZSBub25jZQ=="] {
+            let mut req = req.clone();
+            req.headers_mut().insert("Connection", "Upgrade".parse().unwrap());
+            req.headers_mut().insert("Upgrade", "websocket".parse().unwrap());
+            req.headers_mut().insert("Sec-WebSocket-Version", "13".parse().unwrap());
+            req.headers_mut().insert("Sec-WebSocket-Key", key.parse().unwrap());
+            assert!(matches!(
+                validate_request(&req),
+                Err(Error::Protocol(ProtocolError::InvalidSecWebSocketKey))
+            ));
+        }
+    }
+
+    #[test]
+    fn require_subprotocol() {
+        const DATA: &[u8] = b"\
+            GET /script.ws HTTP/1.1\r\n\
+            Host: foo.com\r\n\
+            Connection: upgrade\r\n\
+            Upgrade: websocket\r\n\
+            Sec-WebSocket-Version: 13\r\n\
+            Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\
+            Sec-WebSocket-Protocol: chat, json\r\n\
+            \r\n";
+        let stream = || Stream { incoming: Cursor::new(DATA.to_vec()), written: vec![] };
+
+        let callback = RequireSubprotocol("json".into());
+        let socket = ServerHandshake::start(stream(), callback, None).handshake().unwrap();
+        assert_eq!(socket.selected_subprotocol(), Some("json"));
+        let response = String::from_utf8(socket.get_ref().written.clone()).unwrap();
+        assert!(response.contains("sec-websocket-protocol: json\r\n"));
+
+        let callback = RequireSubprotocol("xml".into());
+        match ServerHandshake::start(stream(), callback, None).handshake() {
+            Err(HandshakeError::Failure(Error::Http(response))) => {
+                assert_eq!(response.status(), http::StatusCode::BAD_REQUEST);
+            }
+            _ => panic!("expected a 400 response"),
+        }
+    }
+
+    #[test]
+    fn request_tail() {
+        const DATA: &[u8] = b"\
+            GET /script.ws HTTP/1.1\r\n\
+            Host: foo.com\r\n\
+            Connection: upgrade\r\n\
+            Upgrade: websocket\r\n\
+            Sec-WebSocket-Version: 13\r\n\
+            Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\
+            \r\n\
+            \x81\x82\x00\x00\x00\x00hi";
+        let stream = || Stream { incoming: Cursor::new(DATA.to_vec()), written: vec![] };
+
+        assert!(matches!(
+            ServerHandshake::start(stream(), NoCallback, None).handshake(),
+            Err(HandshakeError::Failure(Error::Protocol(ProtocolError::JunkAfterRequest)))
+        ));
+
+        let config = WebSocketConfig::default().allow_request_tail(true);
+        let mut socket =
+            ServerHandshake::start(stream(), NoCallback, Some(config)).handshake().unwrap();
+        assert!(socket.get_ref().written.starts_with(b"HTTP/1.1 101"));
+        assert_eq!(socket.read().unwrap(), Message::text("hi"));
+    }
+
+    #[test]
+    fn start_buffered() {
+        const DATA: &[u8] = b"\
+            GET /script.ws HTTP/1.1\r\n\
+            Host: foo.com\r\n\
+            Connection: upgrade\r\n\
+            Upgrade: websocket\r\n\
+            Sec-WebSocket-Version: 13\r\n\
+            Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\
+            \r\n\
+            \x81\x82\x00\x00\x00\x00hi";
+        let mut reader =
+            BufReader::new(Stream { incoming: Cursor::new(DATA.to_vec()), written: vec![] });
+        // Everything has been read from the stream already.
+        assert_eq!(reader.fill_buf().unwrap(), DATA);
+
+        let config = WebSocketConfig::default().allow_request_tail(true);
+        let mut socket = ServerHandshake::start_buffered(reader, NoCallback, Some(config))
+            .unwrap()
+            .handshake()
+            .unwrap();
+        assert!(socket.get_ref().written.starts_with(b"HTTP/1.1 101"));
+        assert_eq!(socket.read().unwrap(), Message::text("hi"));
+    }
 }
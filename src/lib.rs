@@ -46,8 +46,13 @@ pub use bytes::Bytes;
 pub use crate::{
     client::{client, connect, ClientRequestBuilder},
     handshake::{client::ClientHandshake, server::ServerHandshake, HandshakeError},
-    server::{accept, accept_hdr, accept_hdr_with_config, accept_with_config},
+    server::{
+        accept, accept_hdr, accept_hdr_with_config, accept_with_addr, accept_with_config,
+        accept_with_subprotocol,
+    },
 };
 
+#[cfg(all(feature = "__rustls-tls", feature = "handshake"))]
+pub use tls::default_rustls_config;
 #[cfg(all(any(feature = "native-tls", feature = "__rustls-tls"), feature = "handshake"))]
 pub use tls::{client_tls, client_tls_with_config, Connector};
@@ -0,0 +1,670 @@
+//! Encoding and decoding of WebSocket frames.
+
+use super::{coding::OpCode, mask::apply_mask, Frame, FrameHeader, PreparedFrame};
+use crate::{
+    error::{CapacityError, Error, ProtocolError, Result},
+    Message,
+};
+use bytes::BytesMut;
+use log::*;
+use std::{
+    io::{self, Cursor, Error as IoError, ErrorKind as IoErrorKind, Read, Write},
+    time::{Duration, Instant},
+};
+
+/// A codec for WebSocket frames.
+///
+/// Besides being used internally with a stream, the codec can be driven without doing any I/O
+/// through the `SansIoCodec` trait, available with the `sans-io` feature.
+#[derive(Debug)]
+pub struct FrameCodec {
+    /// Buffer to read data from the stream.
+    in_buffer: BytesMut,
+    /// Capacity up to which `in_buffer` grows while reads keep filling it.
+    max_in_buffer_growth: usize,
+    /// Buffer to send packets to the network.
+    out_buffer: Vec<u8>,
+    /// Capacity limit for `out_buffer`.
+    max_out_buffer_len: usize,
+    /// Buffer target length to reach before writing to the stream
+    /// on calls to `buffer_frame`.
+    ///
+    /// Setting this to non-zero will buffer small writes from hitting
+    /// the stream.
+    out_buffer_write_len: usize,
+    /// Buffer target length to reach before writing to the stream when a control
+    /// frame is buffered. `None` means `out_buffer_write_len` applies.
+    control_out_buffer_write_len: Option<usize>,
+    /// Header and remaining size of the incoming packet being processed.
+    header: Option<(FrameHeader, u64)>,
+    /// Maximum time to receive a frame's payload once its header has been parsed.
+    max_frame_read_time: Option<Duration>,
+    /// When the header of the incoming packet being processed has been parsed.
+    /// Only tracked if `max_frame_read_time` is set.
+    header_received_at: Option<Instant>,
+    /// Maximum number of reads from the stream, or feeds, to complete a partially received
+    /// frame header.
+    max_header_reads: Option<usize>,
+    /// Number of reads or feeds performed since the first bytes of the current frame header arrived.
+    header_reads: usize,
+}
+
+impl FrameCodec {
+    /// Create a new frame codec.
+    pub fn new(in_buf_len: usize) -> Self {
+        Self {
+            in_buffer: BytesMut::with_capacity(in_buf_len),
+            max_in_buffer_growth: in_buf_len,
+            out_buffer: <_>::default(),
+            max_out_buffer_len: usize::MAX,
+            out_buffer_write_len: 0,
+            control_out_buffer_write_len: None,
+            header: None,
+            max_frame_read_time: None,
+            header_received_at: None,
+            max_header_reads: None,
+            header_reads: 0,
+        }
+    }
+
+    /// Create a new frame codec from partially read data.
+    pub fn from_partially_read(part: Vec<u8>, min_in_buf_len: usize) -> Self {
+        let mut in_buffer = BytesMut::from_iter(part);
+        in_buffer.reserve(min_in_buf_len.saturating_sub(in_buffer.len()));
+        Self {
+            in_buffer,
+            max_in_buffer_growth: min_in_buf_len,
+            out_buffer: <_>::default(),
+            max_out_buffer_len: usize::MAX,
+            out_buffer_write_len: 0,
+            control_out_buffer_write_len: None,
+            header: None,
+            max_frame_read_time: None,
+            header_received_at: None,
+            max_header_reads: None,
+            header_reads: 0,
+        }
+    }
+
+    /// Sets the capacity up to which the in buffer grows while reads keep filling it.
+    pub(crate) fn set_max_in_buffer_growth(&mut self, max: usize) {
+        self.max_in_buffer_growth = max;
+    }
+
+    /// Sets a maximum size for the out buffer.
+    pub(crate) fn set_max_out_buffer_len(&mut self, max: usize) {
+        self.max_out_buffer_len = max;
+    }
+
+    /// Sets [`Self::buffer_frame`] buffer target length to reach before
+    /// writing to the stream.
+    pub(crate) fn set_out_buffer_write_len(&mut self, len: usize) {
+        self.out_buffer_write_len = len;
+    }
+
+    /// Sets the buffer target length to reach before writing to the stream when a
+    /// control frame is buffered, see [`Self::set_out_buffer_write_len`].
+    pub(crate) fn set_control_out_buffer_write_len(&mut self, len: Option<usize>) {
+        self.control_out_buffer_write_len = len;
+    }
+
+    /// The buffer target length to reach before writing a frame with the given opcode.
+    fn out_buffer_write_len(&self, opcode: OpCode) -> usize {
+        match opcode {
+            OpCode::Control(_) => {
+                self.control_out_buffer_write_len.unwrap_or(self.out_buffer_write_len)
+            }
+            OpCode::Data(_) => self.out_buffer_write_len,
+        }
+    }
+
+    /// Sets the maximum time to receive a frame's payload once its header has been parsed.
+    pub(crate) fn set_max_frame_read_time(&mut self, max: Option<Duration>) {
+        self.max_frame_read_time = max;
+    }
+
+    /// Sets the maximum number of reads from the stream, or feeds, to complete a frame header.
+    pub(crate) fn set_max_header_reads(&mut self, max: Option<usize>) {
+        self.max_header_reads = max;
+    }
+
+    /// Read a frame from the provided stream.
+    pub(crate) fn read_frame(
+        &mut self,
+        stream: &mut impl Read,
+        max_size: Option<usize>,
+        unmask: bool,
+        accept_unmasked: bool,
+    ) -> Result<Option<Frame>> {
+        loop {
+            if let Some(frame) = self.poll_read_frame(max_size, unmask, accept_unmasked)? {
+                return Ok(Some(frame));
+            }
+
+            // Not enough data in buffer.
+            let partial_header = self.has_partial_header();
+            self.in_buffer.reserve(self.header.as_ref().map(|(_, l)| *l as usize).unwrap_or(6));
+            if self.read_in(stream)? == 0 {
+                trace!("no frame received");
+                return Ok(None);
+            }
+            if partial_header {
+                self.header_reads += 1;
+            }
+        }
+    }
+
+    /// Returns `true` if only a part of the next frame header has been received.
+    fn has_partial_header(&self) -> bool {
+        self.header.is_none() && !self.in_buffer.is_empty()
+    }
+
+    /// Decode the next frame from the data already in the input buffer.
+    ///
+    /// Returns `Ok(None)` if the buffered data doesn't contain a complete frame yet.
+    /// Fails if the frame or its header takes longer to receive than allowed.
+    pub(crate) fn poll_read_frame(
+        &mut self,
+        max_size: Option<usize>,
+        unmask: bool,
+        accept_unmasked: bool,
+    ) -> Result<Option<Frame>> {
+        let max_size = max_size.unwrap_or_else(usize::max_value);
+
+        if self.header.is_none() {
+            let mut cursor = Cursor::new(&mut self.in_buffer);
+            self.header = FrameHeader::parse(&mut cursor)?;
+            let advanced = cursor.position();
+            bytes::Buf::advance(&mut self.in_buffer, advanced as _);
+            if self.header.is_some() {
+                self.header_reads = 0;
+                if self.max_frame_read_time.is_some() {
+                    self.header_received_at = Some(Instant::now());
+                }
+            }
+        }
+
+        let mut payload = match &self.header {
+            Some((_, len)) => {
+                let len = *len as usize;
+
+                // Enforce frame size limit early and make sure `length`
+                // is not too big (fits into `usize`).
+                if len > max_size {
+                    return Err(Error::Capacity(CapacityError::MessageTooLong {
+                        size: len,
+                        max_size,
+                    }));
+                }
+
+                if len > self.in_buffer.len() {
+                    return self.check_incomplete_frame().map(|()| None);
+                }
+                self.in_buffer.split_to(len)
+            }
+            None => return self.check_incomplete_frame().map(|()| None),
+        };
+
+        let (mut header, length) = self.header.take().expect("Bug: no frame header");
+        self.header_received_at = None;
+        debug_assert_eq!(payload.len() as u64, length);
+
+        if unmask {
+            if let Some(mask) = header.mask.take() {
+                // A server MUST remove masking for data frames received from a client
+                // as described in Section 5.3. (RFC 6455)
+                apply_mask(&mut payload, mask);
+            } else if !accept_unmasked {
+                // The server MUST close the connection upon receiving a
+                // frame that is not masked. (RFC 6455)
+                // The only exception here is if the user explicitly accepts given
+                // stream by setting WebSocketConfig.accept_unmasked_frames to true
+                return Err(Error::Protocol(ProtocolError::UnmaskedFrameFromClient));
+            }
+        }
+
+        let frame = Frame::from_payload(header, payload.freeze());
+        trace!("received frame {frame}");
+        Ok(Some(frame))
+    }
+
+    /// Check the limits on receiving the frame which is not complete yet.
+    fn check_incomplete_frame(&self) -> Result<()> {
+        // Don't let a peer keep us waiting for the rest of the frame forever.
+        if let (Some(max), Some(received_at)) = (self.max_frame_read_time, self.header_received_at)
+        {
+            if received_at.elapsed() > max {
+                return Err(Error::Protocol(ProtocolError::FrameReadTimeout));
+            }
+        }
+        // Don't let a peer dribble the frame header byte by byte either.
+        if let Some(max) = self.max_header_reads {
+            if self.has_partial_header() && self.header_reads >= max {
+                return Err(Error::Protocol(ProtocolError::FrameHeaderReadLimit));
+            }
+        }
+        Ok(())
+    }
+
+    /// Read into available `in_buffer` capacity.
+    fn read_in(&mut self, stream: &mut impl Read) -> io::Result<usize> {
+        let len = self.in_buffer.len();
+        let capacity = self.in_buffer.capacity();
+        debug_assert!(capacity > len);
+        self.in_buffer.resize(capacity, 0);
+        let size = stream.read(&mut self.in_buffer[len..]);
+        self.in_buffer.truncate(len + size.as_ref().copied().unwrap_or(0));
+        // The buffer was filled entirely, so more data is likely to come. Grow it to
+        // read more at once, up to the configured size.
+        if size.as_ref().map_or(false, |&size| len + size == capacity)
+            && capacity < self.max_in_buffer_growth
+        {
+            let target = capacity.saturating_mul(2).min(self.max_in_buffer_growth);
+            self.in_buffer.reserve(target - self.in_buffer.len());
+        }
+        size
+    }
+
+    /// Writes a frame into the `out_buffer`.
+    /// If the out buffer size is over the `out_buffer_write_len`, or
+    /// `control_out_buffer_write_len` for control frames, will also write
+    /// the out buffer into the provided `stream`.
+    ///
+    /// To ensure buffered frames are written call [`Self::write_out_buffer`].
+    ///
+    /// May write to the stream, will **not** flush.
+    pub(crate) fn buffer_frame<Stream>(&mut self, stream: &mut Stream, frame: Frame) -> Result<()>
+    where
+        Stream: Write,
+    {
+        let write_len = self.out_buffer_write_len(frame.header().opcode);
+        self.queue_frame(frame)?;
+
+        if self.out_buffer.len() > write_len {
+            self.write_out_buffer(stream)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Writes a prepared frame into the `out_buffer`, like [`buffer_frame`](Self::buffer_frame),
+    /// masking it if `masked` is set.
+    pub(crate) fn buffer_prepared_frame<Stream>(
+        &mut self,
+        stream: &mut Stream,
+        frame: &PreparedFrame,
+        masked: bool,
+    ) -> Result<()>
+    where
+        Stream: Write,
+    {
+        if frame.len(masked) + self.out_buffer.len() > self.max_out_buffer_len {
+            return Err(Error::WriteBufferFull(Message::Frame(frame.frame().clone())));
+        }
+
+        trace!("writing prepared frame {}", frame.frame());
+
+        self.out_buffer.reserve(frame.len(masked));
+        frame.format_into_buf(&mut self.out_buffer, masked);
+
+        if self.out_buffer.len() > self.out_buffer_write_len(frame.frame().header().opcode) {
+            self.write_out_buffer(stream)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Queue a frame for sending without writing it anywhere.
+    ///
+    /// If the out buffer would exceed its maximum size
+    /// [`Err(WriteBufferFull(msg_frame))`](Error::WriteBufferFull) is returned.
+    pub(crate) fn queue_frame(&mut self, frame: Frame) -> Result<()> {
+        if frame.len() + self.out_buffer.len() > self.max_out_buffer_len {
+            return Err(Error::WriteBufferFull(Message::Frame(frame)));
+        }
+
+        trace!("writing frame {frame}");
+
+        self.out_buffer.reserve(frame.len());
+        frame.format_into_buf(&mut self.out_buffer).expect("Bug: can't write to vector");
+        Ok(())
+    }
+
+    /// Returns the number of queued output bytes not yet retrieved or written.
+    pub(crate) fn pending_output_len(&self) -> usize {
+        self.out_buffer.len()
+    }
+
+    /// Returns the number of bytes still missing to complete the frame being received.
+    ///
+    /// Returns `None` if no frame header has been parsed yet, as the frame length isn't
+    /// known then. Returns `Some(0)` if the frame is complete but hasn't been read yet.
+    pub(crate) fn bytes_needed(&self) -> Option<usize> {
+        let (_, len) = self.header.as_ref()?;
+        let len = usize::try_from(*len).unwrap_or(usize::MAX);
+        Some(len.saturating_sub(self.in_buffer.len()))
+    }
+
+    /// Returns `true` if data has been received which hasn't been decoded into a frame yet.
+    ///
+    /// This includes incomplete frames, so a `true` doesn't mean that a frame is available.
+    pub(crate) fn has_pending_input(&self) -> bool {
+        !self.in_buffer.is_empty() || self.header.is_some()
+    }
+
+    /// Writes the out_buffer to the provided stream.
+    ///
+    /// Does **not** flush.
+    pub(crate) fn write_out_buffer<Stream>(&mut self, stream: &mut Stream) -> Result<()>
+    where
+        Stream: Write,
+    {
+        while !self.out_buffer.is_empty() {
+            let len = stream.write(&self.out_buffer)?;
+            if len == 0 {
+                // This is the same as "Connection reset by peer"
+                return Err(IoError::new(
+                    IoErrorKind::ConnectionReset,
+                    "Connection reset while sending",
+                )
+                .into());
+            }
+            self.out_buffer.drain(0..len);
+        }
+
+        Ok(())
+    }
+
+    /// Returns the data received but not decoded into a frame yet.
+    pub(crate) fn into_in_buffer(self) -> BytesMut {
+        self.in_buffer
+    }
+}
+
+/// Driving a [`FrameCodec`] without doing any I/O, e.g. from async wrappers.
+///
+/// Received data is passed in with [`feed`](Self::feed) and decoded with
+/// [`poll_read_frame`](Self::poll_read_frame), while frames queued with
+/// [`queue_frame`](Self::queue_frame) are retrieved as bytes with
+/// [`poll_write`](Self::poll_write).
+#[cfg(feature = "sans-io")]
+pub trait SansIoCodec {
+    /// Append received data to the input buffer.
+    fn feed(&mut self, data: &[u8]);
+
+    /// Decode the next frame from the data already fed.
+    ///
+    /// Returns `Ok(None)` if the buffered data doesn't contain a complete frame yet, in which
+    /// case more data should be [`feed`](Self::feed)ed.
+    ///
+    /// If `unmask` is set masked frames are unmasked, and unmasked frames are rejected
+    /// unless `accept_unmasked` is set as well.
+    fn poll_read_frame(
+        &mut self,
+        max_size: Option<usize>,
+        unmask: bool,
+        accept_unmasked: bool,
+    ) -> Result<Option<Frame>>;
+
+    /// Queue a frame for sending, to be retrieved with [`poll_write`](Self::poll_write).
+    ///
+    /// If the queued output would exceed its maximum size
+    /// [`Err(WriteBufferFull(msg_frame))`](Error::WriteBufferFull) is returned.
+    fn queue_frame(&mut self, frame: Frame) -> Result<()>;
+
+    /// Move as much queued output as fits into `buf`, returning the number of bytes copied.
+    ///
+    /// Returns `0` if there is no pending output (or `buf` is empty).
+    fn poll_write(&mut self, buf: &mut [u8]) -> usize;
+
+    /// Returns the number of queued output bytes not yet retrieved.
+    fn pending_output_len(&self) -> usize;
+
+    /// Returns `true` if data has been fed which hasn't been decoded into a frame yet.
+    fn has_pending_input(&self) -> bool;
+
+    /// Returns the number of bytes still missing to complete the frame being received,
+    /// or `None` if no frame header has been decoded yet.
+    fn bytes_needed(&self) -> Option<usize>;
+
+    /// Sets the maximum number of bytes of queued output.
+    fn set_max_out_buffer_len(&mut self, max: usize);
+
+    /// Sets the maximum time to receive a frame's payload once its header has been decoded.
+    fn set_max_frame_read_time(&mut self, max: Option<Duration>);
+
+    /// Sets the maximum number of feeds to complete a partially received frame header.
+    fn set_max_header_reads(&mut self, max: Option<usize>);
+}
+
+#[cfg(feature = "sans-io")]
+impl SansIoCodec for FrameCodec {
+    fn feed(&mut self, data: &[u8]) {
+        if self.has_partial_header() {
+            self.header_reads += 1;
+        }
+        self.in_buffer.extend_from_slice(data);
+    }
+
+    fn poll_read_frame(
+        &mut self,
+        max_size: Option<usize>,
+        unmask: bool,
+        accept_unmasked: bool,
+    ) -> Result<Option<Frame>> {
+        FrameCodec::poll_read_frame(self, max_size, unmask, accept_unmasked)
+    }
+
+    fn queue_frame(&mut self, frame: Frame) -> Result<()> {
+        FrameCodec::queue_frame(self, frame)
+    }
+
+    fn poll_write(&mut self, buf: &mut [u8]) -> usize {
+        let len = buf.len().min(self.out_buffer.len());
+        buf[..len].copy_from_slice(&self.out_buffer[..len]);
+        self.out_buffer.drain(0..len);
+        len
+    }
+
+    fn pending_output_len(&self) -> usize {
+        FrameCodec::pending_output_len(self)
+    }
+
+    fn has_pending_input(&self) -> bool {
+        FrameCodec::has_pending_input(self)
+    }
+
+    fn bytes_needed(&self) -> Option<usize> {
+        FrameCodec::bytes_needed(self)
+    }
+
+    fn set_max_out_buffer_len(&mut self, max: usize) {
+        FrameCodec::set_max_out_buffer_len(self, max);
+    }
+
+    fn set_max_frame_read_time(&mut self, max: Option<Duration>) {
+        FrameCodec::set_max_frame_read_time(self, max);
+    }
+
+    fn set_max_header_reads(&mut self, max: Option<usize>) {
+        FrameCodec::set_max_header_reads(self, max);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Frame, FrameCodec};
+    use crate::error::{Error, ProtocolError};
+
+    use std::io::{self, Cursor, Read, Write};
+
+    #[test]
+    fn write_out_buffer_would_block() {
+        /// Accepts up to `limit` bytes, then blocks.
+        struct Throttled {
+            written: Vec<u8>,
+            limit: usize,
+        }
+
+        impl Write for Throttled {
+            fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+                let len = buf.len().min(self.limit - self.written.len()).min(2);
+                if len == 0 {
+                    return Err(io::ErrorKind::WouldBlock.into());
+                }
+                self.written.extend_from_slice(&buf[..len]);
+                Ok(len)
+            }
+            fn flush(&mut self) -> io::Result<()> {
+                Ok(())
+            }
+        }
+
+        let mut codec = FrameCodec::new(16);
+        codec.queue_frame(Frame::ping(vec![0x01, 0x02, 0x03])).unwrap();
+        let mut stream = Throttled { written: vec![], limit: 3 };
+
+        let err = codec.write_out_buffer(&mut stream).unwrap_err();
+        assert!(matches!(err, Error::Io(e) if e.kind() == io::ErrorKind::WouldBlock));
+        assert_eq!(stream.written, [0x89, 0x03, 0x01]);
+        assert_eq!(codec.pending_output_len(), 2);
+
+        stream.limit = 5;
+        codec.write_out_buffer(&mut stream).unwrap();
+        assert_eq!(stream.written, [0x89, 0x03, 0x01, 0x02, 0x03]);
+        assert_eq!(codec.pending_output_len(), 0);
+    }
+
+    #[test]
+    fn header_read_limit() {
+        /// Returns a single byte per read.
+        struct Dribble(Cursor<Vec<u8>>);
+
+        impl Read for Dribble {
+            fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+                let len = buf.len().min(1);
+                self.0.read(&mut buf[..len])
+            }
+        }
+
+        // 8 byte header with a 64 bit length, followed by the payload.
+        let data = vec![0x82, 0x7f, 0, 0, 0, 0, 0, 0, 0, 0x01, 0xaa];
+
+        let mut codec = FrameCodec::new(16);
+        codec.set_max_header_reads(Some(3));
+        let mut stream = Dribble(Cursor::new(data.clone()));
+        assert!(matches!(
+            codec.read_frame(&mut stream, None, false, false),
+            Err(Error::Protocol(ProtocolError::FrameHeaderReadLimit))
+        ));
+
+        let mut codec = FrameCodec::new(16);
+        codec.set_max_header_reads(Some(9));
+        let mut stream = Dribble(Cursor::new(data));
+        let frame = codec.read_frame(&mut stream, None, false, false).unwrap().unwrap();
+        assert_eq!(frame.payload(), &[0xaa]);
+    }
+
+    #[test]
+    fn bytes_needed() {
+        let mut codec = FrameCodec::new(16);
+        assert_eq!(codec.bytes_needed(), None);
+        assert!(!codec.has_pending_input());
+        assert!(codec.read_frame(&mut Cursor::new([0x82]), None, false, false).unwrap().is_none());
+        assert_eq!(codec.bytes_needed(), None);
+        assert!(codec.has_pending_input());
+        let mut stream = Cursor::new([0x07, 0x01, 0x02]);
+        assert!(codec.read_frame(&mut stream, None, false, false).unwrap().is_none());
+        assert_eq!(codec.bytes_needed(), Some(5));
+        let mut stream = Cursor::new([0x03, 0x04, 0x05, 0x06, 0x07]);
+        assert!(codec.read_frame(&mut stream, None, false, false).unwrap().is_some());
+        assert_eq!(codec.bytes_needed(), None);
+        assert!(!codec.has_pending_input());
+    }
+
+    #[cfg(feature = "sans-io")]
+    #[test]
+    fn sans_io_codec() {
+        use super::SansIoCodec;
+
+        let mut codec = FrameCodec::new(16);
+        codec.queue_frame(Frame::ping(vec![0x01, 0x02])).unwrap();
+        codec.queue_frame(Frame::pong(vec![0x03])).unwrap();
+        assert_eq!(codec.pending_output_len(), 7);
+
+        let mut out = [0; 3];
+        let mut written = vec![];
+        loop {
+            let len = codec.poll_write(&mut out);
+            if len == 0 {
+                break;
+            }
+            written.extend_from_slice(&out[..len]);
+        }
+        assert_eq!(codec.pending_output_len(), 0);
+        assert_eq!(written, [0x89, 0x02, 0x01, 0x02, 0x8a, 0x01, 0x03]);
+
+        let mut codec = FrameCodec::new(16);
+        codec.feed(&written[..3]);
+        assert!(codec.poll_read_frame(None, false, true).unwrap().is_none());
+        codec.feed(&written[3..]);
+        assert_eq!(
+            codec.poll_read_frame(None, false, true).unwrap().unwrap().into_payload(),
+            &[0x01, 0x02][..]
+        );
+        assert_eq!(
+            codec.poll_read_frame(None, false, true).unwrap().unwrap().into_payload(),
+            &[0x03][..]
+        );
+        assert!(codec.poll_read_frame(None, false, true).unwrap().is_none());
+    }
+
+    #[cfg(feature = "sans-io")]
+    #[test]
+    fn sans_io_limits() {
+        use super::SansIoCodec;
+        use std::{thread::sleep, time::Duration};
+
+        let mut codec = FrameCodec::new(16);
+        codec.set_max_header_reads(Some(2));
+        for byte in [0x82, 0x7f] {
+            codec.feed(&[byte]);
+            assert!(codec.poll_read_frame(None, false, false).unwrap().is_none());
+        }
+        codec.feed(&[0]);
+        assert!(matches!(
+            codec.poll_read_frame(None, false, false),
+            Err(Error::Protocol(ProtocolError::FrameHeaderReadLimit))
+        ));
+
+        let mut codec = FrameCodec::new(16);
+        codec.set_max_frame_read_time(Some(Duration::from_millis(10)));
+        codec.feed(&[0x82, 0x07, 0x01]);
+        assert!(codec.poll_read_frame(None, false, false).unwrap().is_none());
+        sleep(Duration::from_millis(20));
+        assert!(matches!(
+            codec.poll_read_frame(None, false, false),
+            Err(Error::Protocol(ProtocolError::FrameReadTimeout))
+        ));
+
+        let mut codec = FrameCodec::new(16);
+        codec.set_max_out_buffer_len(3);
+        assert!(matches!(
+            codec.queue_frame(Frame::ping(vec![0x01, 0x02])),
+            Err(Error::WriteBufferFull(_))
+        ));
+    }
+
+    #[test]
+    fn in_buffer_growth() {
+        let mut raw = Cursor::new(vec![0x82; 64]);
+        let mut codec = FrameCodec::new(8);
+        codec.set_max_in_buffer_growth(32);
+
+        // A read filling the buffer makes it grow.
+        assert_eq!(codec.read_in(&mut raw).unwrap(), 8);
+        assert!(codec.in_buffer.capacity() >= 16);
+        codec.in_buffer.clear();
+        assert!(codec.read_in(&mut raw).unwrap() >= 16);
+    }
+}
@@ -28,6 +28,46 @@ pub struct CloseFrame {
     pub reason: Utf8Bytes,
 }
 
+impl CloseFrame {
+    /// Create a new close frame with the given code and reason.
+    ///
+    /// Returns an error if the code is not allowed to be sent (see [`CloseCode::is_allowed`])
+    /// or if the reason is longer than 123 bytes, which is all that fits into a control frame
+    /// next to the code.
+    pub fn new(code: CloseCode, reason: impl Into<Utf8Bytes>) -> Result<Self> {
+        if !code.is_allowed() {
+            return Err(Error::Protocol(ProtocolError::InvalidCloseCode(code.into())));
+        }
+        let reason = reason.into();
+        if reason.len() > 123 {
+            return Err(Error::Protocol(ProtocolError::ControlFrameTooBig));
+        }
+        Ok(CloseFrame { code, reason })
+    }
+
+    /// A close frame with [`CloseCode::Normal`] and no reason.
+    pub fn normal() -> Self {
+        CloseFrame { code: CloseCode::Normal, reason: Utf8Bytes::default() }
+    }
+
+    /// A close frame with [`CloseCode::Away`] and no reason.
+    pub fn going_away() -> Self {
+        CloseFrame { code: CloseCode::Away, reason: Utf8Bytes::default() }
+    }
+
+    /// A close frame with [`CloseCode::Policy`] and the given reason.
+    ///
+    /// Returns an error if the reason is too long, see [`new`](Self::new).
+    pub fn policy_violation(reason: impl Into<Utf8Bytes>) -> Result<Self> {
+        Self::new(CloseCode::Policy, reason)
+    }
+
+    /// A close frame with [`CloseCode::Size`] and no reason.
+    pub fn message_too_big() -> Self {
+        CloseFrame { code: CloseCode::Size, reason: Utf8Bytes::default() }
+    }
+}
+
 impl fmt::Display for CloseFrame {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         write!(f, "{} ({})", self.reason, self.code)
@@ -86,16 +126,19 @@ impl FrameHeader {
         2 + LengthFormat::for_length(length).extra_bytes() + if self.mask.is_some() { 4 } else { 0 }
     }
 
+    /// Get the reserved bits as they appear in the first byte of the header, i.e. `0x40`
+    /// for RSV1, `0x20` for RSV2 and `0x10` for RSV3.
+    pub fn reserved_bits(&self) -> u8 {
+        (if self.rsv1 { 0x40 } else { 0 })
+            | if self.rsv2 { 0x20 } else { 0 }
+            | if self.rsv3 { 0x10 } else { 0 }
+    }
+
     /// Format a header for given payload size.
     pub fn format(&self, length: u64, output: &mut impl Write) -> Result<()> {
         let code: u8 = self.opcode.into();
 
-        let one = {
-            code | if self.is_final { 0x80 } else { 0 }
-                | if self.rsv1 { 0x40 } else { 0 }
-                | if self.rsv2 { 0x20 } else { 0 }
-                | if self.rsv3 { 0x10 } else { 0 }
-        };
+        let one = code | if self.is_final { 0x80 } else { 0 } | self.reserved_bits();
 
         let lenfmt = LengthFormat::for_length(length);
 
@@ -239,9 +282,42 @@ impl Frame {
         &self.payload
     }
 
+    /// Get the frame's opcode.
+    #[inline]
+    pub fn opcode(&self) -> OpCode {
+        self.header.opcode
+    }
+
+    /// Test whether this is the final frame of a message.
+    ///
+    /// Control frames are always final. A data frame which is not final starts (or, with
+    /// [`Data::Continue`] opcode, continues) a fragmented message.
+    #[inline]
+    pub fn is_final(&self) -> bool {
+        self.header.is_final
+    }
+
+    /// Test whether the first reserved bit is set.
+    #[inline]
+    pub fn rsv1(&self) -> bool {
+        self.header.rsv1
+    }
+
+    /// Test whether the second reserved bit is set.
+    #[inline]
+    pub fn rsv2(&self) -> bool {
+        self.header.rsv2
+    }
+
+    /// Test whether the third reserved bit is set.
+    #[inline]
+    pub fn rsv3(&self) -> bool {
+        self.header.rsv3
+    }
+
     /// Test whether the frame is masked.
     #[inline]
-    pub(crate) fn is_masked(&self) -> bool {
+    pub fn is_masked(&self) -> bool {
         self.header.mask.is_some()
     }
 
@@ -273,14 +349,23 @@ impl Frame {
     }
 
     /// Consume the frame into a closing frame.
+    ///
+    /// If `lossy` is set, a reason which is not valid UTF-8 is converted lossily
+    /// instead of returning an error.
     #[inline]
-    pub(crate) fn into_close(self) -> Result<Option<CloseFrame>> {
+    pub(crate) fn into_close(self, lossy: bool) -> Result<Option<CloseFrame>> {
         match self.payload.len() {
             0 => Ok(None),
             1 => Err(Error::Protocol(ProtocolError::InvalidCloseSequence)),
             _ => {
                 let code = u16::from_be_bytes([self.payload[0], self.payload[1]]).into();
-                let reason = Utf8Bytes::try_from(self.payload.slice(2..))?;
+                let reason = match Utf8Bytes::try_from(self.payload.slice(2..)) {
+                    Ok(reason) => reason,
+                    Err(_) if lossy => {
+                        String::from_utf8_lossy(&self.payload[2..]).into_owned().into()
+                    }
+                    Err(err) => return Err(err.into()),
+                };
                 Ok(Some(CloseFrame { code, reason }))
             }
         }
@@ -369,6 +454,58 @@ impl Frame {
     }
 }
 
+/// A frame with its header formatted in advance, to send the same frame repeatedly.
+///
+/// Whether the frame is masked is decided by the socket writing it, so a prepared frame can
+/// be sent through both client and server sockets. Only the masking key is generated and
+/// applied each time the frame is sent, if the frame is masked. Create one with
+/// [`WebSocket::prepare_frame`](crate::WebSocket::prepare_frame).
+#[derive(Debug, Clone)]
+pub struct PreparedFrame {
+    frame: Frame,
+    /// The formatted header, without the mask bit and masking key.
+    header: Vec<u8>,
+}
+
+impl PreparedFrame {
+    /// Prepare `frame` to be sent repeatedly.
+    pub(crate) fn new(mut frame: Frame) -> Self {
+        frame.header.mask = None;
+        let mut header = Vec::with_capacity(frame.header.len(frame.payload.len() as u64));
+        frame
+            .header
+            .format(frame.payload.len() as u64, &mut header)
+            .expect("Bug: can't write to vector");
+        PreparedFrame { frame, header }
+    }
+
+    /// Get the prepared frame.
+    pub fn frame(&self) -> &Frame {
+        &self.frame
+    }
+
+    /// Get the length of the frame once formatted, with a masking key if `masked` is set.
+    pub(crate) fn len(&self, masked: bool) -> usize {
+        self.frame.len() + if masked { 4 } else { 0 }
+    }
+
+    /// Write the frame out to a buffer, masking it with a new key if `masked` is set.
+    pub(crate) fn format_into_buf(&self, buf: &mut Vec<u8>, masked: bool) {
+        let start = buf.len();
+        buf.extend_from_slice(&self.header);
+        if masked {
+            buf[start + 1] |= 0x80;
+            let mask = generate_mask();
+            buf.extend_from_slice(&mask);
+            let len = buf.len();
+            buf.extend_from_slice(&self.frame.payload);
+            apply_mask(&mut buf[len..], mask);
+        } else {
+            buf.extend_from_slice(&self.frame.payload);
+        }
+    }
+}
+
 impl fmt::Display for Frame {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         use std::fmt::Write;
@@ -486,10 +623,90 @@ mod tests {
         assert_eq!(buf, vec![0x89, 0x02, 0x01, 0x02]);
     }
 
+    #[test]
+    fn prepared_frame() {
+        let frame = Frame::message(vec![0x01, 0x02, 0x03], OpCode::Data(Data::Binary), true);
+
+        let prepared = PreparedFrame::new(frame);
+        let mut buf = vec![];
+        prepared.format_into_buf(&mut buf, false);
+        assert_eq!(buf, [0x82, 0x03, 0x01, 0x02, 0x03]);
+        assert_eq!(buf.len(), prepared.len(false));
+
+        let mut first = vec![];
+        prepared.format_into_buf(&mut first, true);
+        assert_eq!(first.len(), prepared.len(true));
+        assert_eq!(first[..2], [0x82, 0x83]);
+        let mut payload = first[6..].to_vec();
+        apply_mask(&mut payload, first[2..6].try_into().unwrap());
+        assert_eq!(payload, [0x01, 0x02, 0x03]);
+    }
+
+    #[test]
+    fn accessors() {
+        let mut frame = Frame::message(vec![0x01], OpCode::Data(Data::Continue), false);
+        assert_eq!(frame.opcode(), OpCode::Data(Data::Continue));
+        assert!(!frame.is_final());
+        assert!(!frame.rsv1() && !frame.rsv2() && !frame.rsv3());
+        assert!(!frame.is_masked());
+
+        frame.header_mut().rsv1 = true;
+        assert_eq!(frame.header().reserved_bits(), 0x40);
+        frame.set_random_mask();
+        assert!(frame.rsv1());
+        assert!(frame.is_masked());
+
+        let frame = Frame::ping(vec![]);
+        assert_eq!(frame.opcode(), OpCode::Control(Control::Ping));
+        assert!(frame.is_final());
+    }
+
+    #[test]
+    fn close_with_invalid_utf8_reason() {
+        let close = Frame::from_payload(
+            FrameHeader::default(),
+            Bytes::from_static(&[0x03, 0xe8, b'a', 0xff]),
+        );
+        assert!(close.clone().into_close(false).is_err());
+        let close = close.into_close(true).unwrap().unwrap();
+        assert_eq!(close.code, CloseCode::Normal);
+        assert_eq!(close.reason, "a\u{fffd}");
+    }
+
     #[test]
     fn display() {
         let f = Frame::message(Bytes::from_static(b"hi there"), OpCode::Data(Data::Text), true);
         let view = format!("{f}");
         assert!(view.contains("payload:"));
     }
+
+    #[test]
+    fn close_frame_new() {
+        let frame = CloseFrame::new(CloseCode::Normal, "bye").unwrap();
+        assert_eq!(frame.code, CloseCode::Normal);
+        assert_eq!(frame.reason, "bye");
+
+        assert!(CloseFrame::new(CloseCode::Library(4000), "x".repeat(123)).is_ok());
+        assert!(matches!(
+            CloseFrame::new(CloseCode::Normal, "x".repeat(124)),
+            Err(Error::Protocol(ProtocolError::ControlFrameTooBig))
+        ));
+        assert!(matches!(
+            CloseFrame::new(CloseCode::Abnormal, ""),
+            Err(Error::Protocol(ProtocolError::InvalidCloseCode(1006)))
+        ));
+    }
+
+    #[test]
+    fn close_frame_constructors() {
+        assert_eq!(CloseFrame::normal().code, CloseCode::Normal);
+        assert_eq!(CloseFrame::going_away().code, CloseCode::Away);
+        assert_eq!(CloseFrame::message_too_big().code, CloseCode::Size);
+        assert_eq!(CloseFrame::normal().reason, "");
+
+        let frame = CloseFrame::policy_violation("no spam").unwrap();
+        assert_eq!(frame.code, CloseCode::Policy);
+        assert_eq!(frame.reason, "no spam");
+        assert!(CloseFrame::policy_violation("x".repeat(124)).is_err());
+    }
 }
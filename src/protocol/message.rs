@@ -1,6 +1,6 @@
 use super::frame::{CloseFrame, Frame};
 use crate::{
-    error::{CapacityError, Error, Result},
+    error::{CapacityError, Error, ProtocolError, Result},
     protocol::frame::Utf8Bytes,
 };
 use std::{fmt, result::Result as StdResult, str};
@@ -82,12 +82,17 @@ use bytes::Bytes;
 #[derive(Debug)]
 pub struct IncompleteMessage {
     collector: IncompleteMessageCollector,
+    /// Number of frames the message has been assembled from so far.
+    frame_count: usize,
+    /// Payload sizes of the frames so far, if recorded.
+    frame_sizes: Option<Vec<usize>>,
 }
 
 #[derive(Debug)]
 enum IncompleteMessageCollector {
     Text(StringCollector),
     Binary(Vec<u8>),
+    RawText(Vec<u8>),
 }
 
 impl IncompleteMessage {
@@ -99,15 +104,34 @@ impl IncompleteMessage {
                 IncompleteMessageType::Text => {
                     IncompleteMessageCollector::Text(StringCollector::new())
                 }
+                IncompleteMessageType::RawText => IncompleteMessageCollector::RawText(Vec::new()),
             },
+            frame_count: 0,
+            frame_sizes: None,
         }
     }
 
+    /// Record the payload sizes of the frames added to the message.
+    pub fn record_frame_sizes(&mut self) {
+        self.frame_sizes = Some(Vec::new());
+    }
+
+    /// Get the number of frames added to the message so far.
+    pub fn frame_count(&self) -> usize {
+        self.frame_count
+    }
+
+    /// Take the payload sizes of the frames added to the message so far, if recorded.
+    pub fn take_frame_sizes(&mut self) -> Option<Vec<usize>> {
+        self.frame_sizes.take()
+    }
+
     /// Get the current filled size of the buffer.
     pub fn len(&self) -> usize {
         match self.collector {
             IncompleteMessageCollector::Text(ref t) => t.len(),
             IncompleteMessageCollector::Binary(ref b) => b.len(),
+            IncompleteMessageCollector::RawText(ref b) => b.len(),
         }
     }
 
@@ -126,8 +150,13 @@ impl IncompleteMessage {
             }));
         }
 
+        self.frame_count += 1;
+        if let Some(ref mut sizes) = self.frame_sizes {
+            sizes.push(portion_size);
+        }
         match self.collector {
-            IncompleteMessageCollector::Binary(ref mut v) => {
+            IncompleteMessageCollector::Binary(ref mut v)
+            | IncompleteMessageCollector::RawText(ref mut v) => {
                 v.extend(tail.as_ref());
                 Ok(())
             }
@@ -143,6 +172,10 @@ impl IncompleteMessage {
                 let text = t.into_string()?;
                 Ok(Message::text(text))
             }
+            IncompleteMessageCollector::RawText(v) => match String::from_utf8(v) {
+                Ok(text) => Ok(Message::text(text)),
+                Err(e) => Ok(Message::Binary(e.into_bytes().into())),
+            },
         }
     }
 }
@@ -151,6 +184,8 @@ impl IncompleteMessage {
 pub enum IncompleteMessageType {
     Text,
     Binary,
+    /// Text which is only validated once complete, and returned as binary if it isn't UTF-8.
+    RawText,
 }
 
 /// An enum representing the various forms of a WebSocket message.
@@ -163,10 +198,16 @@ pub enum Message {
     /// A ping message with the specified payload
     ///
     /// The payload here must have a length less than 125 bytes
+    ///
+    /// A received payload is not copied, it references the connection's read buffer.
+    /// Dropping it promptly allows the read buffer memory to be reused.
     Ping(Bytes),
     /// A pong message with the specified payload
     ///
     /// The payload here must have a length less than 125 bytes
+    ///
+    /// A received payload is not copied, it references the connection's read buffer.
+    /// Dropping it promptly allows the read buffer memory to be reused.
     Pong(Bytes),
     /// A close message with the optional close frame.
     Close(Option<CloseFrame>),
@@ -174,6 +215,14 @@ pub enum Message {
     Frame(Frame),
 }
 
+/// Check that the payload fits into a control frame.
+fn control_payload(data: Bytes) -> Result<Bytes> {
+    if data.len() > 125 {
+        return Err(Error::Protocol(ProtocolError::ControlFrameTooBig));
+    }
+    Ok(data)
+}
+
 impl Message {
     /// Create a new text WebSocket message from a stringable.
     pub fn text<S>(string: S) -> Message
@@ -191,6 +240,26 @@ impl Message {
         Message::Binary(bin.into())
     }
 
+    /// Create a new ping WebSocket message by converting to `Bytes`.
+    ///
+    /// Returns an error if the payload is longer than the 125 bytes allowed for control frames.
+    pub fn ping<B>(data: B) -> Result<Message>
+    where
+        B: Into<Bytes>,
+    {
+        Ok(Message::Ping(control_payload(data.into())?))
+    }
+
+    /// Create a new pong WebSocket message by converting to `Bytes`.
+    ///
+    /// Returns an error if the payload is longer than the 125 bytes allowed for control frames.
+    pub fn pong<B>(data: B) -> Result<Message>
+    where
+        B: Into<Bytes>,
+    {
+        Ok(Message::Pong(control_payload(data.into())?))
+    }
+
     /// Indicates whether a message is a text message.
     pub fn is_text(&self) -> bool {
         matches!(*self, Message::Text(_))
@@ -362,4 +431,15 @@ mod tests {
         let msg = Message::from(s);
         assert!(msg.is_text());
     }
+
+    #[test]
+    fn control_constructors() {
+        assert_eq!(Message::ping(vec![1, 2]).unwrap(), Message::Ping(vec![1, 2].into()));
+        assert_eq!(Message::pong(&b"pong"[..]).unwrap(), Message::Pong(b"pong"[..].into()));
+        assert!(Message::ping(vec![0; 125]).is_ok());
+        assert!(matches!(
+            Message::pong(vec![0; 126]),
+            Err(Error::Protocol(ProtocolError::ControlFrameTooBig))
+        ));
+    }
 }
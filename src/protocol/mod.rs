@@ -9,18 +9,29 @@ pub use self::{frame::CloseFrame, message::Message};
 use self::{
     frame::{
         coding::{CloseCode, Control as OpCtl, Data as OpData, OpCode},
-        Frame, FrameCodec,
+        Frame, FrameCodec, PreparedFrame,
     },
     message::{IncompleteMessage, IncompleteMessageType},
 };
+#[cfg(feature = "handshake")]
+use crate::handshake::client::Response;
 use crate::{
     error::{CapacityError, Error, ProtocolError, Result},
     protocol::frame::Utf8Bytes,
+    util::NonBlockingResult,
 };
+use bytes::Bytes;
 use log::*;
 use std::{
+    fmt,
     io::{self, Read, Write},
     mem::replace,
+    ops::Deref,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::Duration,
 };
 
 /// Indicates a Client or Server role of the websocket
@@ -32,6 +43,67 @@ pub enum Role {
     Client,
 }
 
+/// How to reply to a close frame initiated by the peer, see [`WebSocket::set_close_reply`].
+///
+/// The reply is built from the close frame received from the peer, with disallowed close codes
+/// already replaced by [`CloseCode::Protocol`].
+#[derive(Clone, Default)]
+#[non_exhaustive]
+pub enum CloseReply {
+    /// Echo the received close frame, which is the default.
+    #[default]
+    Echo,
+    /// Always reply with the given close frame.
+    Fixed(CloseFrame),
+    /// Build the reply from the received close frame. `None` sends a close frame without a
+    /// close code.
+    Custom(Arc<CloseReplyFn>),
+}
+
+/// A function building the reply to a close frame received from the peer, see
+/// [`CloseReply::Custom`].
+pub type CloseReplyFn = dyn Fn(Option<&CloseFrame>) -> Option<CloseFrame> + Send + Sync;
+
+impl CloseReply {
+    /// Build the reply to the given close frame received from the peer.
+    fn build(&self, close: Option<&CloseFrame>) -> Option<CloseFrame> {
+        match self {
+            CloseReply::Echo => close.cloned(),
+            CloseReply::Fixed(reply) => Some(reply.clone()),
+            CloseReply::Custom(build) => build(close),
+        }
+    }
+}
+
+impl fmt::Debug for CloseReply {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CloseReply::Echo => f.write_str("Echo"),
+            CloseReply::Fixed(reply) => f.debug_tuple("Fixed").field(reply).finish(),
+            CloseReply::Custom(_) => f.write_str("Custom(..)"),
+        }
+    }
+}
+
+/// A function notified when the peer closed the connection without a closing handshake,
+/// see [`WebSocket::set_on_abnormal_close`].
+///
+/// The function is given the number of frames received from the peer.
+pub type AbnormalCloseFn = dyn FnMut(u64) + Send + Sync;
+
+/// The function set with [`WebSocket::set_on_abnormal_close`], if any.
+#[derive(Default)]
+struct OnAbnormalClose(Option<Box<AbnormalCloseFn>>);
+
+impl fmt::Debug for OnAbnormalClose {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.0 {
+            Some(_) => f.write_str("Some(..)"),
+            None => f.write_str("None"),
+        }
+    }
+}
+
 /// The configuration for WebSocket connection.
 ///
 /// # Example
@@ -46,6 +118,12 @@ pub enum Role {
 pub struct WebSocketConfig {
     /// Read buffer capacity. The default value is 128 KiB.
     pub read_buffer_size: usize,
+    /// Initial read buffer capacity. `None` means [`read_buffer_size`](Self::read_buffer_size),
+    /// which is the default.
+    ///
+    /// Setting this to a smaller value reduces the memory used by idle connections. The read
+    /// buffer then starts small and grows up to `read_buffer_size` while reads keep filling it.
+    pub initial_read_buffer_size: Option<usize>,
     /// The target minimum size of the write buffer to reach before writing the data
     /// to the underlying stream.
     /// The default value is 128 KiB.
@@ -55,6 +133,14 @@ pub struct WebSocketConfig {
     ///
     /// Note: [`flush`](WebSocket::flush) will always fully write the buffer regardless.
     pub write_buffer_size: usize,
+    /// The target minimum size of the write buffer to reach before writing the data
+    /// to the underlying stream when a control frame (ping, pong or close) is written.
+    /// `None` means [`Self::write_buffer_size`] applies. The default value is `None`.
+    ///
+    /// Setting this to `Some(0)` writes control frames, together with any data buffered
+    /// before them, to the stream right away, so e.g. heartbeats go out with low latency
+    /// while data messages are still batched.
+    pub control_write_buffer_size: Option<usize>,
     /// The max size of the write buffer in bytes. Setting this can provide backpressure
     /// in the case the write buffer is filling up due to write errors.
     /// The default value is unlimited.
@@ -69,6 +155,10 @@ pub struct WebSocketConfig {
     /// The maximum size of an incoming message. `None` means no size limit. The default value is 64 MiB
     /// which should be reasonably big for all normal use-cases but small enough to prevent
     /// memory eating by a malicious user.
+    ///
+    /// **Without a limit a peer can make this endpoint buffer an arbitrary amount of data**,
+    /// so only disable it for trusted peers, preferably with
+    /// [`unlimited_message_size`](Self::unlimited_message_size) which makes this explicit.
     pub max_message_size: Option<usize>,
     /// The maximum size of a single incoming message frame. `None` means no size limit. The limit is for
     /// frame payload NOT including the frame header. The default value is 16 MiB which should
@@ -80,18 +170,144 @@ pub struct WebSocketConfig {
     /// connection to the client in such cases, however it seems like there are
     /// some popular libraries that are sending unmasked frames, ignoring the RFC.
     /// By default this option is set to `false`, i.e. according to RFC 6455.
+    ///
+    /// This only applies to servers, a warning is logged if it is set for a client.
     pub accept_unmasked_frames: bool,
+    /// When set to `true`, the server always requires client frames to be masked, overriding
+    /// [`Self::accept_unmasked_frames`]. This guarantees RFC 6455 compliant masking
+    /// regardless of other settings. The default value is `false`.
+    pub strict_masking: bool,
+    /// The reserved bits which are defined by an extension handled by the application, as
+    /// they appear in the first byte of the frame header, see
+    /// [`FrameHeader::reserved_bits`](frame::FrameHeader::reserved_bits).
+    /// Received frames with other reserved bits set fail the connection with
+    /// [`ProtocolError::NonZeroReservedBits`], and so does writing such a [`Message::Frame`].
+    /// The default value is `0`, i.e. no reserved bits may be set.
+    pub extension_reserved_bits: u8,
+    /// When set to `true`, a client sends its close frames unmasked.
+    ///
+    /// **This violates RFC 6455**, which requires clients to mask all frames. It is only meant
+    /// as a debugging aid against servers which fail on masked close frames, and has no effect
+    /// for servers. By default this option is set to `false`.
+    pub unmasked_client_close: bool,
+    /// When set to `true`, pings are only answered automatically if their payload is empty.
+    /// Pings carrying a payload are still returned by [`read`](WebSocket::read), but it's up to
+    /// you to reply to them (or not).
+    /// By default this option is set to `false`, i.e. all pings are answered automatically.
+    pub auto_pong_only_empty: bool,
+    /// The maximum time to receive the payload of an incoming frame once its header has been
+    /// received. `None` means no limit, which is the default.
+    ///
+    /// Setting this protects against peers announcing a big frame and then sending its payload
+    /// very slowly in order to tie up resources. If the limit is exceeded, reading fails with
+    /// [`ProtocolError::FrameReadTimeout`].
+    ///
+    /// Note: The limit is checked when reading from the stream, so for blocking streams it is
+    /// only effective together with a read timeout set on the stream.
+    pub max_frame_read_time: Option<Duration>,
+    /// The maximum number of reads from the stream to complete a frame header once its first
+    /// bytes have been received. `None` means no limit, which is the default.
+    ///
+    /// A frame header is at most 14 bytes long, so a peer sending it in many small pieces is
+    /// likely trying to tie up resources. If the limit is exceeded, reading fails with
+    /// [`ProtocolError::FrameHeaderReadLimit`].
+    pub max_frame_header_reads: Option<usize>,
+    /// The maximum amount of payload data accepted from the peer after we sent a close frame
+    /// and before the peer replied with its own. `None` means no limit, which is the default.
+    ///
+    /// The peer may still send messages it queued before receiving our close frame, but it
+    /// should not be able to flood us indefinitely. If the limit is exceeded the connection is
+    /// terminated and reading fails with [`ProtocolError::TooMuchDataAfterClose`].
+    pub max_data_after_close: Option<usize>,
+    /// When set to `true`, a client keeps a copy of the server's handshake response which can
+    /// then be accessed with [`WebSocket::handshake_response`]. The default value is `false`,
+    /// since most users don't need it after the handshake.
+    pub store_handshake_response: bool,
+    /// When set to `true`, a copy of the raw handshake head received from the peer is kept.
+    /// A client attaches it to the handshake response as
+    /// [`RawResponseHeaders`](crate::handshake::client::RawResponseHeaders) extension, a server
+    /// to the request passed to the callback as
+    /// [`RawRequest`](crate::handshake::server::RawRequest) extension.
+    /// The default value is `false`, which avoids copying the head of every handshake.
+    pub keep_raw_handshake: bool,
+    /// The maximum payload size of an incoming close frame. `None` means the limit of 125 bytes
+    /// mandated by RFC 6455 for all control frames, which is the default.
+    ///
+    /// Some misbehaving peers send close frames with longer reasons, which are rejected with
+    /// [`ProtocolError::ControlFrameTooBig`] by default. Raising this limit allows to receive
+    /// them as [`Message::Close`] with the complete reason, e.g. for diagnostics. The reason is
+    /// truncated in the close frame sent back as reply.
+    pub max_close_payload_size: Option<usize>,
+    /// When set to `true`, a close frame received with a close code which is not allowed on the
+    /// wire is returned as is by [`read`](WebSocket::read), e.g. to log the peer's code. The
+    /// reply still uses [`CloseCode::Protocol`].
+    ///
+    /// By default this option is set to `false`, i.e. the returned close frame is replaced by
+    /// the reply with [`CloseCode::Protocol`] and the reason "Protocol violation".
+    pub keep_invalid_close_code: bool,
+    /// When set to `true`, a close frame with [`CloseCode::Protocol`] is sent when the peer
+    /// closes the connection without a closing handshake, before reading fails with
+    /// [`ProtocolError::ResetWithoutClosingHandshake`]. Errors writing the frame are ignored,
+    /// as the peer may be gone already.
+    /// The default value is `false`.
+    ///
+    /// This lets a peer which only shut down its sending side see why the connection ends.
+    pub close_on_abnormal_close: bool,
+    /// When set to `true`, a close reason which is not valid UTF-8 is converted lossily,
+    /// replacing invalid sequences with `U+FFFD`, instead of failing the connection.
+    /// The default value is `false`.
+    ///
+    /// RFC 6455 only says the reason SHOULD be UTF-8, and some peers send other data.
+    pub lossy_close_reason: bool,
+    /// When set to `true`, a text message which is not valid UTF-8 is returned as
+    /// [`Message::Binary`] holding the raw data, instead of failing with [`Error::Utf8`].
+    /// The default value is `false`.
+    ///
+    /// This is meant for debugging peers sending broken text messages. Note that fragmented
+    /// text messages are then only validated once complete.
+    pub invalid_text_as_binary: bool,
+    /// The maximum size of the response body kept in [`Error::Http`] when a server rejects the
+    /// client handshake. Any data beyond is dropped. The default value is 64 KiB.
+    pub max_handshake_error_body_size: usize,
+    /// When set to `true`, the payload sizes of the frames each received message was
+    /// assembled from are recorded, see [`WebSocket::last_message_frame_sizes`].
+    /// The default value is `false`.
+    pub record_frame_sizes: bool,
+    /// When set to `true`, a server accepts data sent by the client right after the
+    /// handshake request, before the handshake response, and reads it as WebSocket frames.
+    /// When set to `false` such data fails the handshake with
+    /// [`ProtocolError::JunkAfterRequest`]. The default value is `false`.
+    pub allow_request_tail: bool,
 }
 
 impl Default for WebSocketConfig {
     fn default() -> Self {
         Self {
             read_buffer_size: 128 * 1024,
+            initial_read_buffer_size: None,
             write_buffer_size: 128 * 1024,
+            control_write_buffer_size: None,
             max_write_buffer_size: usize::MAX,
             max_message_size: Some(64 << 20),
             max_frame_size: Some(16 << 20),
             accept_unmasked_frames: false,
+            strict_masking: false,
+            extension_reserved_bits: 0,
+            unmasked_client_close: false,
+            auto_pong_only_empty: false,
+            max_frame_read_time: None,
+            max_frame_header_reads: None,
+            max_data_after_close: None,
+            store_handshake_response: false,
+            keep_raw_handshake: false,
+            max_close_payload_size: None,
+            keep_invalid_close_code: false,
+            close_on_abnormal_close: false,
+            lossy_close_reason: false,
+            invalid_text_as_binary: false,
+            max_handshake_error_body_size: 64 << 10,
+            record_frame_sizes: false,
+            allow_request_tail: false,
         }
     }
 }
@@ -103,12 +319,24 @@ impl WebSocketConfig {
         self
     }
 
+    /// Set [`Self::initial_read_buffer_size`].
+    pub fn initial_read_buffer_size(mut self, initial_read_buffer_size: Option<usize>) -> Self {
+        self.initial_read_buffer_size = initial_read_buffer_size;
+        self
+    }
+
     /// Set [`Self::write_buffer_size`].
     pub fn write_buffer_size(mut self, write_buffer_size: usize) -> Self {
         self.write_buffer_size = write_buffer_size;
         self
     }
 
+    /// Set [`Self::control_write_buffer_size`].
+    pub fn control_write_buffer_size(mut self, control_write_buffer_size: Option<usize>) -> Self {
+        self.control_write_buffer_size = control_write_buffer_size;
+        self
+    }
+
     /// Set [`Self::max_write_buffer_size`].
     pub fn max_write_buffer_size(mut self, max_write_buffer_size: usize) -> Self {
         self.max_write_buffer_size = max_write_buffer_size;
@@ -121,6 +349,19 @@ impl WebSocketConfig {
         self
     }
 
+    /// Disable the limit of [`Self::max_message_size`].
+    ///
+    /// **This allows a peer to make this endpoint buffer an arbitrary amount of data**, so it
+    /// should only be used for trusted peers. A warning is logged the first time this is called.
+    pub fn unlimited_message_size(mut self) -> Self {
+        static WARNED: AtomicBool = AtomicBool::new(false);
+        if !WARNED.swap(true, Ordering::Relaxed) {
+            warn!("WebSocketConfig::max_message_size is disabled, message sizes are not limited");
+        }
+        self.max_message_size = None;
+        self
+    }
+
     /// Set [`Self::max_frame_size`].
     pub fn max_frame_size(mut self, max_frame_size: Option<usize>) -> Self {
         self.max_frame_size = max_frame_size;
@@ -133,6 +374,113 @@ impl WebSocketConfig {
         self
     }
 
+    /// Set [`Self::strict_masking`].
+    pub fn strict_masking(mut self, strict_masking: bool) -> Self {
+        self.strict_masking = strict_masking;
+        self
+    }
+
+    /// Set [`Self::extension_reserved_bits`].
+    pub fn extension_reserved_bits(mut self, extension_reserved_bits: u8) -> Self {
+        self.extension_reserved_bits = extension_reserved_bits;
+        self
+    }
+
+    /// Set [`Self::unmasked_client_close`].
+    pub fn unmasked_client_close(mut self, unmasked_client_close: bool) -> Self {
+        self.unmasked_client_close = unmasked_client_close;
+        self
+    }
+
+    /// Set [`Self::auto_pong_only_empty`].
+    pub fn auto_pong_only_empty(mut self, auto_pong_only_empty: bool) -> Self {
+        self.auto_pong_only_empty = auto_pong_only_empty;
+        self
+    }
+
+    /// Set [`Self::max_frame_read_time`].
+    pub fn max_frame_read_time(mut self, max_frame_read_time: Option<Duration>) -> Self {
+        self.max_frame_read_time = max_frame_read_time;
+        self
+    }
+
+    /// Set [`Self::max_frame_header_reads`].
+    pub fn max_frame_header_reads(mut self, max_frame_header_reads: Option<usize>) -> Self {
+        self.max_frame_header_reads = max_frame_header_reads;
+        self
+    }
+
+    /// Set [`Self::max_data_after_close`].
+    pub fn max_data_after_close(mut self, max_data_after_close: Option<usize>) -> Self {
+        self.max_data_after_close = max_data_after_close;
+        self
+    }
+
+    /// Set [`Self::store_handshake_response`].
+    pub fn store_handshake_response(mut self, store_handshake_response: bool) -> Self {
+        self.store_handshake_response = store_handshake_response;
+        self
+    }
+
+    /// Set [`Self::keep_raw_handshake`].
+    pub fn keep_raw_handshake(mut self, keep_raw_handshake: bool) -> Self {
+        self.keep_raw_handshake = keep_raw_handshake;
+        self
+    }
+
+    /// Set [`Self::max_close_payload_size`].
+    pub fn max_close_payload_size(mut self, max_close_payload_size: Option<usize>) -> Self {
+        self.max_close_payload_size = max_close_payload_size;
+        self
+    }
+
+    /// Set [`Self::keep_invalid_close_code`].
+    pub fn keep_invalid_close_code(mut self, keep_invalid_close_code: bool) -> Self {
+        self.keep_invalid_close_code = keep_invalid_close_code;
+        self
+    }
+
+    /// Set [`Self::close_on_abnormal_close`].
+    pub fn close_on_abnormal_close(mut self, close_on_abnormal_close: bool) -> Self {
+        self.close_on_abnormal_close = close_on_abnormal_close;
+        self
+    }
+
+    /// Set [`Self::lossy_close_reason`].
+    pub fn lossy_close_reason(mut self, lossy_close_reason: bool) -> Self {
+        self.lossy_close_reason = lossy_close_reason;
+        self
+    }
+
+    /// Set [`Self::invalid_text_as_binary`].
+    pub fn invalid_text_as_binary(mut self, invalid_text_as_binary: bool) -> Self {
+        self.invalid_text_as_binary = invalid_text_as_binary;
+        self
+    }
+
+    /// Set [`Self::max_handshake_error_body_size`].
+    pub fn max_handshake_error_body_size(mut self, max_handshake_error_body_size: usize) -> Self {
+        self.max_handshake_error_body_size = max_handshake_error_body_size;
+        self
+    }
+
+    /// Set [`Self::record_frame_sizes`].
+    pub fn record_frame_sizes(mut self, record_frame_sizes: bool) -> Self {
+        self.record_frame_sizes = record_frame_sizes;
+        self
+    }
+
+    /// Set [`Self::allow_request_tail`].
+    pub fn allow_request_tail(mut self, allow_request_tail: bool) -> Self {
+        self.allow_request_tail = allow_request_tail;
+        self
+    }
+
+    /// The capacity to allocate the read buffer with.
+    fn initial_read_buffer_len(&self) -> usize {
+        self.initial_read_buffer_size.unwrap_or(self.read_buffer_size)
+    }
+
     /// Panic if values are invalid.
     pub(crate) fn assert_valid(&self) {
         assert!(
@@ -141,6 +489,83 @@ impl WebSocketConfig {
             see WebSocketConfig docs`"
         );
     }
+
+    /// Warn about settings which have no effect for the given role.
+    fn check_role(&self, role: Role) {
+        if role == Role::Client && self.accept_unmasked_frames {
+            warn!(
+                "WebSocketConfig::accept_unmasked_frames has no effect for clients, \
+                servers must not mask their frames"
+            );
+        }
+        if self.strict_masking && self.accept_unmasked_frames {
+            warn!("WebSocketConfig::accept_unmasked_frames is overridden by strict_masking");
+        }
+    }
+}
+
+/// A [`WebSocketConfig`] that can be cheaply shared between many connections.
+///
+/// Cloning a `SharedConfig` only bumps a reference count, so it is a good fit for servers
+/// which apply the same configuration to every accepted connection. WebSockets created with
+/// [`WebSocket::from_raw_socket_with_shared_config`] keep referring to the shared config
+/// instead of holding a copy of their own, until it is changed with
+/// [`WebSocket::set_config`] for one of them.
+///
+/// # Example
+/// ```
+/// # use tungstenite::protocol::{SharedConfig, WebSocketConfig};
+/// let shared = SharedConfig::new(WebSocketConfig::default().max_message_size(Some(1 << 20)));
+/// assert_eq!(shared.max_message_size, Some(1 << 20));
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct SharedConfig(Arc<WebSocketConfig>);
+
+impl SharedConfig {
+    /// Wrap the given config so it can be shared.
+    pub fn new(config: WebSocketConfig) -> Self {
+        Self(Arc::new(config))
+    }
+}
+
+impl From<WebSocketConfig> for SharedConfig {
+    fn from(config: WebSocketConfig) -> Self {
+        Self::new(config)
+    }
+}
+
+impl Deref for SharedConfig {
+    type Target = WebSocketConfig;
+
+    fn deref(&self) -> &WebSocketConfig {
+        &self.0
+    }
+}
+
+/// The limits currently applied to a WebSocket connection, see [`WebSocket::effective_limits`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct Limits {
+    /// The maximum size of an incoming message, see [`WebSocketConfig::max_message_size`].
+    pub max_message_size: Option<usize>,
+    /// The maximum size of an incoming frame, see [`WebSocketConfig::max_frame_size`].
+    pub max_frame_size: Option<usize>,
+    /// The max size of the write buffer, see [`WebSocketConfig::max_write_buffer_size`].
+    pub max_write_buffer_size: usize,
+}
+
+/// What happened during a [`WebSocket::write_batch`] call.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct BatchWrite {
+    /// The number of messages queued.
+    pub messages: usize,
+    /// The number of bytes written to the stream.
+    pub bytes_written: usize,
+    /// The number of bytes left in the write buffer, which still need to be flushed.
+    pub bytes_buffered: usize,
+    /// Whether the stream was flushed.
+    pub flushed: bool,
 }
 
 /// WebSocket input-output stream.
@@ -170,6 +595,21 @@ impl<Stream> WebSocket<Stream> {
         WebSocket { socket: stream, context: WebSocketContext::new(role, config) }
     }
 
+    /// Convert a raw socket into a WebSocket without performing a handshake,
+    /// using a config shared with other connections.
+    ///
+    /// See [`WebSocket::from_raw_socket`].
+    ///
+    /// # Panics
+    /// Panics if config is invalid e.g. `max_write_buffer_size <= write_buffer_size`.
+    pub fn from_raw_socket_with_shared_config(
+        stream: Stream,
+        role: Role,
+        config: &SharedConfig,
+    ) -> Self {
+        WebSocket { socket: stream, context: WebSocketContext::with_shared_config(role, config) }
+    }
+
     /// Convert a raw socket into a WebSocket without performing a handshake.
     ///
     /// Call this function if you're using Tungstenite as a part of a web framework
@@ -212,6 +652,30 @@ impl<Stream> WebSocket<Stream> {
         self.context.get_config()
     }
 
+    /// Set how to reply to a close frame initiated by the peer.
+    ///
+    /// By default the received close frame is echoed back. The peer's close frame is returned
+    /// by [`read`](Self::read) as is, regardless of the reply.
+    pub fn set_close_reply(&mut self, close_reply: CloseReply) {
+        self.context.set_close_reply(close_reply);
+    }
+
+    /// Set a function called when the peer closes the connection without completing the
+    /// closing handshake, right before reading fails with
+    /// [`ProtocolError::ResetWithoutClosingHandshake`].
+    ///
+    /// This corresponds to the close code 1006 ([`CloseCode::Abnormal`]) and is meant for
+    /// logging and metrics. The function is given the number of frames received from the peer,
+    /// which tells apart connections dropped before anything was received.
+    pub fn set_on_abnormal_close(&mut self, on_abnormal_close: Box<AbnormalCloseFn>) {
+        self.context.set_on_abnormal_close(on_abnormal_close);
+    }
+
+    /// Get the size limits currently applied to the connection.
+    pub fn effective_limits(&self) -> Limits {
+        self.context.effective_limits()
+    }
+
     /// Check if it is possible to read messages.
     ///
     /// Reading is impossible after receiving `Message::Close`. It is still possible after
@@ -226,9 +690,137 @@ impl<Stream> WebSocket<Stream> {
     pub fn can_write(&self) -> bool {
         self.context.can_write()
     }
+
+    /// Get the number of frames the last message returned by [`read`](Self::read) was
+    /// received in.
+    ///
+    /// This is `1` for messages received as a single frame and greater for fragmented
+    /// messages. Returns `0` if no message has been read yet.
+    pub fn last_message_frame_count(&self) -> usize {
+        self.context.last_message_frame_count()
+    }
+
+    /// Get the payload sizes of the frames the last message returned by [`read`](Self::read)
+    /// was received in.
+    ///
+    /// This is only recorded if [`WebSocketConfig::record_frame_sizes`] is set, otherwise
+    /// the returned slice is empty.
+    pub fn last_message_frame_sizes(&self) -> &[usize] {
+        self.context.last_message_frame_sizes()
+    }
+
+    /// Get the number of messages returned by [`read`](Self::read) so far, including
+    /// control messages.
+    pub fn messages_read(&self) -> u64 {
+        self.context.messages_read()
+    }
+
+    /// Get the number of text and binary messages queued for sending so far.
+    ///
+    /// A fragmented message is counted once its final frame has been queued. Messages rejected
+    /// with [`Error::WriteBufferFull`] are not counted.
+    pub fn messages_written(&self) -> u64 {
+        self.context.messages_written()
+    }
+
+    /// Check if all written data has been passed to the underlying stream.
+    ///
+    /// Returns `true` if the write buffer is empty and no automatic responses like
+    /// pongs or close replies are pending, so it's safe to shut down the write direction
+    /// after flushing the stream.
+    pub fn is_write_empty(&self) -> bool {
+        self.context.is_write_empty()
+    }
+
+    /// Get the frame queued to be sent automatically, e.g. a pong replying to a ping or the
+    /// reply to a close frame, if it hasn't been written yet.
+    ///
+    /// This is meant for tests and requires the `testing` feature.
+    #[cfg(any(test, feature = "testing"))]
+    pub fn pending_auto_frame(&self) -> Option<&Frame> {
+        self.context.pending_auto_frame()
+    }
+
+    /// Check if data has been received from the peer which hasn't been processed yet.
+    ///
+    /// This doesn't perform any I/O and only looks at the read buffer. The pending data may
+    /// be an incomplete frame, so [`read`](Self::read) may still block or return
+    /// [`WouldBlock`](std::io::ErrorKind::WouldBlock).
+    pub fn has_pending_input(&self) -> bool {
+        self.context.has_pending_input()
+    }
+
+    /// Get the number of bytes still needed to complete the frame currently being received.
+    ///
+    /// This allows to size the next read precisely. Returns `None` if no frame header has been
+    /// received yet.
+    pub fn bytes_needed_for_current_frame(&self) -> Option<usize> {
+        self.context.bytes_needed_for_current_frame()
+    }
+
+    /// Get the share of control frames among all frames received so far, from `0.0` to `1.0`.
+    ///
+    /// A high ratio means the peer mostly sends pings and pongs, which may indicate abuse.
+    /// Returns `0.0` if no frame has been received yet.
+    pub fn control_frame_ratio(&self) -> f64 {
+        self.context.control_frame_ratio()
+    }
+
+    /// Get the server's response to the client handshake.
+    ///
+    /// This is only available for clients with [`WebSocketConfig::store_handshake_response`]
+    /// enabled, otherwise `None` is returned.
+    #[cfg(feature = "handshake")]
+    pub fn handshake_response(&self) -> Option<&Response> {
+        self.context.handshake_response.as_ref()
+    }
+
+    /// Get the subprotocol selected by the server during the handshake, if any.
+    ///
+    /// This is `None` for sockets which weren't created by a handshake.
+    #[cfg(feature = "handshake")]
+    pub fn selected_subprotocol(&self) -> Option<&str> {
+        self.context.selected_subprotocol()
+    }
+
+    #[cfg(feature = "handshake")]
+    pub(crate) fn set_handshake_response(&mut self, response: Response) {
+        self.context.handshake_response = Some(response);
+    }
+
+    #[cfg(feature = "handshake")]
+    pub(crate) fn set_selected_subprotocol(&mut self, subprotocol: Option<String>) {
+        self.context.subprotocol = subprotocol;
+    }
 }
 
 impl<Stream: Read + Write> WebSocket<Stream> {
+    /// Convert a raw socket into a WebSocket without performing a handshake, queueing the
+    /// given messages to be sent.
+    ///
+    /// This is handy when reconnecting, to resend messages which couldn't be delivered on
+    /// the previous connection. The messages are written like with [`write`](Self::write),
+    /// so they are sent at the latest on the next [`flush`](Self::flush).
+    ///
+    /// Returns an error if a message can't be queued, e.g. because it is an invalid raw frame
+    /// or the write buffer is full.
+    ///
+    /// # Panics
+    /// Panics if config is invalid e.g. `max_write_buffer_size <= write_buffer_size`.
+    pub fn from_raw_socket_with_pending(
+        stream: Stream,
+        role: Role,
+        config: Option<WebSocketConfig>,
+        pending: Vec<Message>,
+    ) -> Result<Self> {
+        let mut socket = Self::from_raw_socket(stream, role, config);
+        for message in pending {
+            // The message is queued even if writing to the stream would block.
+            socket.write(message).no_block()?;
+        }
+        Ok(socket)
+    }
+
     /// Read a message from stream, if possible.
     ///
     /// This will also queue responses to ping and close messages. These responses
@@ -242,10 +834,40 @@ impl<Stream: Read + Write> WebSocket<Stream> {
     /// You should continue calling [`read`](Self::read), [`write`](Self::write) or
     /// [`flush`](Self::flush) to drive the reply to the close frame until [`Error::ConnectionClosed`]
     /// is returned. Once that happens it is safe to drop the underlying connection.
+    ///
+    /// # Interrupted reads
+    /// If the stream fails with [`WouldBlock`](io::ErrorKind::WouldBlock) or another I/O error
+    /// such as `Interrupted`, any partially received frame or fragmented message is kept,
+    /// so calling `read` again resumes where it stopped. This makes it safe to use from
+    /// event loops which only retry once the stream is ready again.
     pub fn read(&mut self) -> Result<Message> {
         self.context.read(&mut self.socket)
     }
 
+    /// Read all messages that are available without blocking.
+    ///
+    /// The first message is read exactly like [`read`](Self::read) does. After that, every
+    /// further message that can be decoded from the already buffered data is returned too,
+    /// without reading from the stream again. Reading stops after a close message.
+    ///
+    /// If an error occurs after the first message, reading stops and the messages read so
+    /// far are returned. The error is kept and returned by the next call to
+    /// [`read`](Self::read) or `read_buffered`.
+    pub fn read_buffered(&mut self) -> Result<Vec<Message>> {
+        let mut messages = vec![self.read()?];
+        while !messages.last().map_or(false, Message::is_close) {
+            match self.context.read(&mut BufferedOnly(&mut self.socket)).no_block() {
+                Ok(Some(message)) => messages.push(message),
+                Ok(None) => break,
+                Err(err) => {
+                    self.context.deferred_error = Some(err);
+                    break;
+                }
+            }
+        }
+        Ok(messages)
+    }
+
     /// Writes and immediately flushes a message.
     /// Equivalent to calling [`write`](Self::write) then [`flush`](Self::flush).
     pub fn send(&mut self, message: Message) -> Result<()> {
@@ -299,12 +921,156 @@ impl<Stream: Read + Write> WebSocket<Stream> {
         self.context.flush(&mut self.socket)
     }
 
+    /// Flush writes only if more than [`write_buffer_size`](WebSocketConfig::write_buffer_size)
+    /// bytes were queued since the last flush, or an automatic reply is waiting to be flushed.
+    ///
+    /// Bytes already written to the stream count as well, as the stream may buffer them
+    /// itself until flushed.
+    ///
+    /// Returns `true` if it flushed like [`flush`](Self::flush) does. This allows batching
+    /// writes and paying for flushing the stream only once enough data is queued.
+    pub fn flush_if_full(&mut self) -> Result<bool> {
+        self.context.flush_if_full(&mut self.socket)
+    }
+
+    /// Prepare a message to be sent repeatedly, e.g. a heartbeat.
+    ///
+    /// The frame header is formatted once, so sending the prepared frame with
+    /// [`write_prepared`](Self::write_prepared) only has to generate a new masking key, if the
+    /// frame needs to be masked. Raw control frames are checked like by [`write`](Self::write).
+    /// Masking is decided when the frame is written, so it may be sent through any socket.
+    pub fn prepare_frame(&self, message: Message) -> Result<PreparedFrame> {
+        self.context.prepare_frame(message)
+    }
+
+    /// Write a frame prepared with [`prepare_frame`](Self::prepare_frame) to the stream,
+    /// if possible.
+    ///
+    /// This works like [`write`](Self::write), so a subsequent call should be made to
+    /// [`flush`](Self::flush). A prepared close frame starts the close handshake like
+    /// [`close`](Self::close) does.
+    pub fn write_prepared(&mut self, frame: &PreparedFrame) -> Result<()> {
+        self.context.write_prepared(&mut self.socket, frame)
+    }
+
+    /// Write several messages, reporting how they were buffered.
+    ///
+    /// This is equivalent to calling [`write`](Self::write) for each message, but returns
+    /// how many bytes went to the stream and how many are still buffered, which allows
+    /// callers to adapt their batch sizes. As with `write`, a call to [`flush`](Self::flush)
+    /// should follow. If writing a message fails, the error is returned and the remaining
+    /// messages are not written.
+    pub fn write_batch<I>(&mut self, messages: I) -> Result<BatchWrite>
+    where
+        I: IntoIterator<Item = Message>,
+    {
+        let mut stream = CountingStream { stream: &mut self.socket, written: 0, flushed: false };
+        let mut messages_written = 0;
+        for message in messages {
+            self.context.write(&mut stream, message)?;
+            messages_written += 1;
+        }
+        Ok(BatchWrite {
+            messages: messages_written,
+            bytes_written: stream.written,
+            bytes_buffered: self.context.frame.pending_output_len(),
+            flushed: stream.flushed,
+        })
+    }
+
+    /// Send a message with the contents of `reader`, without loading it into memory entirely.
+    ///
+    /// The data is read in chunks of up to `chunk_size` bytes until EOF and each chunk is
+    /// sent as a frame of a fragmented message of the given `kind` (text or binary), like with
+    /// [`begin_message`](Self::begin_message), [`write_frame_chunk`](Self::write_frame_chunk)
+    /// and [`end_message`](Self::end_message). The message is flushed once it has been
+    /// written completely.
+    ///
+    /// For text messages it's up to you to make sure that `reader` provides valid UTF-8,
+    /// the peer is going to fail the connection otherwise.
+    ///
+    /// This is meant to be used with blocking streams. If an error is returned the message
+    /// may have been sent partially, in which case sending further data messages fails with
+    /// [`ProtocolError::MessageInProgress`].
+    ///
+    /// # Panics
+    /// Panics if `kind` is neither [`Data::Text`](frame::coding::Data::Text) nor
+    /// [`Data::Binary`](frame::coding::Data::Binary) or if `chunk_size` is `0`.
+    pub fn send_reader(
+        &mut self,
+        kind: OpData,
+        mut reader: impl Read,
+        chunk_size: usize,
+    ) -> Result<()> {
+        assert!(chunk_size > 0, "Chunk size must be greater than 0");
+
+        self.begin_message(kind)?;
+        loop {
+            let chunk = read_chunk(&mut reader, chunk_size)?;
+            if chunk.is_empty() {
+                break;
+            }
+            self.write_frame_chunk(chunk)?;
+        }
+        self.end_message()?;
+        self.flush()
+    }
+
+    /// Start sending a fragmented message of the given `kind` (text or binary).
+    ///
+    /// The message is sent frame by frame with [`write_frame_chunk`](Self::write_frame_chunk)
+    /// and finished with [`end_message`](Self::end_message). Until then, sending any other data
+    /// frame fails with [`ProtocolError::MessageInProgress`], be it a text or binary message, a
+    /// raw or prepared data frame or [`send_reader`](Self::send_reader). Control messages like
+    /// pings may still be sent in between.
+    ///
+    /// Nothing is written until the first chunk. Starting another message before the current
+    /// one has ended fails with [`ProtocolError::MessageInProgress`].
+    ///
+    /// # Panics
+    /// Panics if `kind` is neither [`Data::Text`](frame::coding::Data::Text) nor
+    /// [`Data::Binary`](frame::coding::Data::Binary).
+    pub fn begin_message(&mut self, kind: OpData) -> Result<()> {
+        self.context.begin_message(kind)
+    }
+
+    /// Write the next chunk of the message started with [`begin_message`](Self::begin_message)
+    /// as a frame.
+    ///
+    /// This works like [`write`](Self::write), so a subsequent call should be made to
+    /// [`flush`](Self::flush). Fails with [`ProtocolError::MessageNotStarted`] if no message
+    /// has been started. For text messages it's up to you to make sure that the chunks
+    /// make up valid UTF-8, a chunk may however end in the middle of a character.
+    ///
+    /// If the chunk is rejected, e.g. with [`Error::WriteBufferFull`], the next chunk continues
+    /// the message in its place. I/O errors occur only after the chunk has been buffered.
+    pub fn write_frame_chunk(&mut self, data: impl Into<Bytes>) -> Result<()> {
+        self.context.write_frame_chunk(&mut self.socket, data)
+    }
+
+    /// Finish the message started with [`begin_message`](Self::begin_message) by writing
+    /// an empty final frame.
+    ///
+    /// This works like [`write`](Self::write), so a subsequent call should be made to
+    /// [`flush`](Self::flush). Fails with [`ProtocolError::MessageNotStarted`] if no message
+    /// has been started.
+    pub fn end_message(&mut self) -> Result<()> {
+        self.context.end_message(&mut self.socket)
+    }
+
     /// Close the connection.
     ///
     /// This function guarantees that the close frame will be queued.
     /// There is no need to call it again. Calling this function is
     /// the same as calling `write(Message::Close(..))`.
     ///
+    /// Close codes that must not be sent on the wire, like [`CloseCode::Abnormal`]
+    /// or [`CloseCode::Status`], are rejected with [`ProtocolError::InvalidCloseCode`].
+    ///
+    /// This is also the case if the write buffer is full (see
+    /// [`WebSocketConfig::max_write_buffer_size`]), the close frame is then kept aside
+    /// and written out as soon as the buffered data has been written to the stream.
+    ///
     /// After queuing the close frame you should continue calling [`read`](Self::read) or
     /// [`flush`](Self::flush) to drive the close handshake to completion.
     ///
@@ -324,6 +1090,31 @@ impl<Stream: Read + Write> WebSocket<Stream> {
         self.context.close(&mut self.socket, code)
     }
 
+    /// Close the connection and drive the close handshake to completion.
+    ///
+    /// This sends a close frame like [`close`](Self::close), then keeps reading until
+    /// [`Error::ConnectionClosed`] is returned, discarding any other incoming messages.
+    /// Returns the close frame sent by the peer, if any.
+    ///
+    /// This is meant for blocking streams, with a non-blocking stream it fails with
+    /// [`Error::Io`] of kind [`WouldBlock`](io::ErrorKind::WouldBlock) and must be called again.
+    pub fn close_and_drain(&mut self, code: Option<CloseFrame>) -> Result<Option<CloseFrame>> {
+        match self.close(code) {
+            Ok(()) => {}
+            Err(Error::ConnectionClosed) => return Ok(None),
+            Err(err) => return Err(err),
+        }
+        let mut peer_close = None;
+        loop {
+            match self.read() {
+                Ok(Message::Close(close)) => peer_close = close,
+                Ok(_) => {}
+                Err(Error::ConnectionClosed) => return Ok(peer_close),
+                Err(err) => return Err(err),
+            }
+        }
+    }
+
     /// Old name for [`read`](Self::read).
     #[deprecated(note = "Use `read`")]
     pub fn read_message(&mut self) -> Result<Message> {
@@ -354,13 +1145,44 @@ pub struct WebSocketContext {
     state: WebSocketState,
     /// Receive: an incomplete message being processed.
     incomplete: Option<IncompleteMessage>,
+    /// Send: opcode of the next frame of a fragmented message started with `begin_message`.
+    outgoing_fragment: Option<OpCode>,
+    /// Receive: number of frames the last complete message consisted of.
+    last_message_frame_count: usize,
+    /// Payload sizes of the frames the last message was received in, if recorded.
+    last_message_frame_sizes: Vec<usize>,
+    /// Number of messages returned by `read`.
+    messages_read: u64,
+    /// Number of data messages queued for sending.
+    messages_written: u64,
+    /// Receive: number of control frames received.
+    control_frames_received: u64,
+    /// Receive: number of data frames received.
+    data_frames_received: u64,
+    /// Receive: payload bytes received since we sent a close frame.
+    received_after_close: usize,
+    /// Receive: an error hit by `WebSocket::read_buffered` after some messages were read,
+    /// returned by the next read.
+    deferred_error: Option<Error>,
+    /// The server's response to the client handshake, if configured to be kept.
+    #[cfg(feature = "handshake")]
+    handshake_response: Option<Response>,
+    /// The subprotocol selected in the handshake.
+    #[cfg(feature = "handshake")]
+    subprotocol: Option<String>,
     /// Send in addition to regular messages E.g. "pong" or "close".
     additional_send: Option<Frame>,
     /// True indicates there is an additional message (like a pong)
     /// that failed to flush previously and we should try again.
     unflushed_additional: bool,
-    /// The configuration for the websocket session.
-    config: WebSocketConfig,
+    /// Send: bytes of frames queued since the stream was last flushed.
+    unflushed_len: usize,
+    /// The configuration for the websocket session, possibly shared with other sessions.
+    config: Arc<WebSocketConfig>,
+    /// How to reply to a close frame initiated by the peer.
+    close_reply: CloseReply,
+    /// Called when the peer closes the connection without a closing handshake.
+    on_abnormal_close: OnAbnormalClose,
 }
 
 impl WebSocketContext {
@@ -369,31 +1191,65 @@ impl WebSocketContext {
     /// # Panics
     /// Panics if config is invalid e.g. `max_write_buffer_size <= write_buffer_size`.
     pub fn new(role: Role, config: Option<WebSocketConfig>) -> Self {
-        let conf = config.unwrap_or_default();
-        Self::_new(role, FrameCodec::new(conf.read_buffer_size), conf)
+        Self::with_shared_config(role, &config.unwrap_or_default().into())
     }
 
-    /// Create a WebSocket context that manages an post-handshake stream.
+    /// Create a WebSocket context that manages a post-handshake stream,
+    /// using a config shared with other connections.
     ///
     /// # Panics
     /// Panics if config is invalid e.g. `max_write_buffer_size <= write_buffer_size`.
-    pub fn from_partially_read(part: Vec<u8>, role: Role, config: Option<WebSocketConfig>) -> Self {
+    pub fn with_shared_config(role: Role, config: &SharedConfig) -> Self {
+        let frame = FrameCodec::new(config.initial_read_buffer_len());
+        Self::_new(role, frame, config.0.clone())
+    }
+
+    /// Create a WebSocket context that manages an post-handshake stream.
+    ///
+    /// # Panics
+    /// Panics if config is invalid e.g. `max_write_buffer_size <= write_buffer_size`.
+    pub fn from_partially_read(part: Vec<u8>, role: Role, config: Option<WebSocketConfig>) -> Self {
         let conf = config.unwrap_or_default();
-        Self::_new(role, FrameCodec::from_partially_read(part, conf.read_buffer_size), conf)
+        Self::_new(
+            role,
+            FrameCodec::from_partially_read(part, conf.initial_read_buffer_len()),
+            Arc::new(conf),
+        )
     }
 
-    fn _new(role: Role, mut frame: FrameCodec, config: WebSocketConfig) -> Self {
+    fn _new(role: Role, mut frame: FrameCodec, config: Arc<WebSocketConfig>) -> Self {
         config.assert_valid();
+        config.check_role(role);
+        frame.set_max_in_buffer_growth(config.read_buffer_size);
         frame.set_max_out_buffer_len(config.max_write_buffer_size);
         frame.set_out_buffer_write_len(config.write_buffer_size);
+        frame.set_control_out_buffer_write_len(config.control_write_buffer_size);
+        frame.set_max_frame_read_time(config.max_frame_read_time);
+        frame.set_max_header_reads(config.max_frame_header_reads);
         Self {
             role,
             frame,
             state: WebSocketState::Active,
             incomplete: None,
+            outgoing_fragment: None,
+            last_message_frame_count: 0,
+            last_message_frame_sizes: Vec::new(),
+            messages_read: 0,
+            messages_written: 0,
+            control_frames_received: 0,
+            data_frames_received: 0,
+            received_after_close: 0,
+            deferred_error: None,
+            #[cfg(feature = "handshake")]
+            handshake_response: None,
+            #[cfg(feature = "handshake")]
+            subprotocol: None,
             additional_send: None,
             unflushed_additional: false,
+            unflushed_len: 0,
             config,
+            close_reply: CloseReply::default(),
+            on_abnormal_close: OnAbnormalClose::default(),
         }
     }
 
@@ -402,10 +1258,15 @@ impl WebSocketContext {
     /// # Panics
     /// Panics if config is invalid e.g. `max_write_buffer_size <= write_buffer_size`.
     pub fn set_config(&mut self, set_func: impl FnOnce(&mut WebSocketConfig)) {
-        set_func(&mut self.config);
+        set_func(Arc::make_mut(&mut self.config));
         self.config.assert_valid();
+        self.config.check_role(self.role);
+        self.frame.set_max_in_buffer_growth(self.config.read_buffer_size);
         self.frame.set_max_out_buffer_len(self.config.max_write_buffer_size);
         self.frame.set_out_buffer_write_len(self.config.write_buffer_size);
+        self.frame.set_control_out_buffer_write_len(self.config.control_write_buffer_size);
+        self.frame.set_max_frame_read_time(self.config.max_frame_read_time);
+        self.frame.set_max_header_reads(self.config.max_frame_header_reads);
     }
 
     /// Read the configuration.
@@ -413,6 +1274,27 @@ impl WebSocketContext {
         &self.config
     }
 
+    /// Set how to reply to a close frame initiated by the peer, see
+    /// [`WebSocket::set_close_reply`].
+    pub fn set_close_reply(&mut self, close_reply: CloseReply) {
+        self.close_reply = close_reply;
+    }
+
+    /// Set a function called when the peer closes the connection without a closing handshake,
+    /// see [`WebSocket::set_on_abnormal_close`].
+    pub fn set_on_abnormal_close(&mut self, on_abnormal_close: Box<AbnormalCloseFn>) {
+        self.on_abnormal_close = OnAbnormalClose(Some(on_abnormal_close));
+    }
+
+    /// Get the size limits currently applied to the connection.
+    pub fn effective_limits(&self) -> Limits {
+        Limits {
+            max_message_size: self.config.max_message_size,
+            max_frame_size: self.config.max_frame_size,
+            max_write_buffer_size: self.config.max_write_buffer_size,
+        }
+    }
+
     /// Check if it is possible to read messages.
     ///
     /// Reading is impossible after receiving `Message::Close`. It is still possible after
@@ -428,6 +1310,86 @@ impl WebSocketContext {
         self.state.is_active()
     }
 
+    /// Get the number of frames the last message returned by [`read`](Self::read) was
+    /// received in.
+    ///
+    /// This is `1` for messages received as a single frame and greater for fragmented
+    /// messages. Returns `0` if no message has been read yet.
+    pub fn last_message_frame_count(&self) -> usize {
+        self.last_message_frame_count
+    }
+
+    /// Get the payload sizes of the frames the last message returned by [`read`](Self::read)
+    /// was received in.
+    ///
+    /// This is only recorded if [`WebSocketConfig::record_frame_sizes`] is set, otherwise
+    /// the returned slice is empty.
+    pub fn last_message_frame_sizes(&self) -> &[usize] {
+        &self.last_message_frame_sizes
+    }
+
+    /// Get the number of messages returned by [`read`](Self::read) so far, including
+    /// control messages.
+    pub fn messages_read(&self) -> u64 {
+        self.messages_read
+    }
+
+    /// Get the number of text and binary messages queued for sending so far.
+    pub fn messages_written(&self) -> u64 {
+        self.messages_written
+    }
+
+    /// Check if all written data has been passed to the stream.
+    ///
+    /// Returns `true` if the write buffer is empty and no automatic responses like
+    /// pongs or close replies are pending.
+    pub fn is_write_empty(&self) -> bool {
+        self.frame.pending_output_len() == 0
+            && self.additional_send.is_none()
+            && !self.unflushed_additional
+    }
+
+    /// Get the frame queued to be sent automatically, e.g. a pong replying to a ping or the
+    /// reply to a close frame, if it hasn't been written yet.
+    ///
+    /// This is meant for tests and requires the `testing` feature.
+    #[cfg(any(test, feature = "testing"))]
+    pub fn pending_auto_frame(&self) -> Option<&Frame> {
+        self.additional_send.as_ref()
+    }
+
+    /// Check if data has been received from the peer which hasn't been processed yet.
+    ///
+    /// The pending data may be an incomplete frame.
+    pub fn has_pending_input(&self) -> bool {
+        self.frame.has_pending_input()
+    }
+
+    /// Get the subprotocol selected by the server during the handshake, if any.
+    #[cfg(feature = "handshake")]
+    pub fn selected_subprotocol(&self) -> Option<&str> {
+        self.subprotocol.as_deref()
+    }
+
+    /// Get the number of bytes still needed to complete the frame currently being received.
+    ///
+    /// Returns `None` if no frame header has been received yet.
+    pub fn bytes_needed_for_current_frame(&self) -> Option<usize> {
+        self.frame.bytes_needed()
+    }
+
+    /// Get the share of control frames among all frames received so far, from `0.0` to `1.0`.
+    ///
+    /// Returns `0.0` if no frame has been received yet.
+    pub fn control_frame_ratio(&self) -> f64 {
+        let total = self.control_frames_received + self.data_frames_received;
+        if total == 0 {
+            0.0
+        } else {
+            self.control_frames_received as f64 / total as f64
+        }
+    }
+
     /// Read a message from the provided stream, if possible.
     ///
     /// This function sends pong and close responses automatically.
@@ -436,6 +1398,9 @@ impl WebSocketContext {
     where
         Stream: Read + Write,
     {
+        if let Some(err) = self.deferred_error.take() {
+            return Err(err);
+        }
         // Do not read from already closed connections.
         self.state.check_not_terminated()?;
 
@@ -459,6 +1424,7 @@ impl WebSocketContext {
             // Thus if read blocks, just let it return WouldBlock.
             if let Some(message) = self.read_message_frame(stream)? {
                 trace!("Received message {message}");
+                self.messages_read += 1;
                 return Ok(message);
             }
         }
@@ -474,6 +1440,9 @@ impl WebSocketContext {
     ///
     /// If the write buffer would exceed the configured [`WebSocketConfig::max_write_buffer_size`]
     /// [`Err(WriteBufferFull(msg_frame))`](Error::WriteBufferFull) is returned.
+    ///
+    /// A raw control frame passed as [`Message::Frame`] is rejected with a protocol error if it is
+    /// fragmented or its payload is longer than 125 bytes.
     pub fn write<Stream>(&mut self, stream: &mut Stream, message: Message) -> Result<()>
     where
         Stream: Read + Write,
@@ -483,9 +1452,15 @@ impl WebSocketContext {
 
         // Do not write after sending a close frame.
         if !self.state.is_active() {
-            return Err(Error::Protocol(ProtocolError::SendAfterClosing));
+            return Err(Error::Protocol(ProtocolError::SendAfterClosing(message)));
         }
 
+        let is_data = matches!(message, Message::Text(_) | Message::Binary(_));
+        if is_data {
+            self.check_no_message_in_progress(OpCode::Data(OpData::Binary))?;
+        } else if let Message::Frame(f) = &message {
+            self.check_no_message_in_progress(f.header().opcode)?;
+        }
         let frame = match message {
             Message::Text(data) => Frame::message(data, OpCode::Data(OpData::Text), true),
             Message::Binary(data) => Frame::message(data, OpCode::Data(OpData::Binary), true),
@@ -496,10 +1471,139 @@ impl WebSocketContext {
                 return self._write(stream, None).map(|_| ());
             }
             Message::Close(code) => return self.close(stream, code),
-            Message::Frame(f) => f,
+            Message::Frame(f) => {
+                check_control_frame(&f)?;
+                check_reserved_bits(&f, self.config.extension_reserved_bits)?;
+                f
+            }
         };
 
-        let should_flush = self._write(stream, Some(frame))?;
+        let completes = completes_message(&frame);
+        let result = self._write(stream, Some(frame));
+        if completes && !matches!(result, Err(Error::WriteBufferFull(_))) {
+            self.messages_written += 1;
+        }
+        if result? {
+            self.flush(stream)?;
+        }
+        Ok(())
+    }
+
+    /// Start sending a fragmented message, see [`WebSocket::begin_message`].
+    pub fn begin_message(&mut self, kind: OpData) -> Result<()> {
+        assert!(matches!(kind, OpData::Text | OpData::Binary), "Invalid message kind");
+        if self.outgoing_fragment.is_some() {
+            return Err(Error::Protocol(ProtocolError::MessageInProgress));
+        }
+        self.outgoing_fragment = Some(OpCode::Data(kind));
+        Ok(())
+    }
+
+    /// Send the next fragment of a message, see [`WebSocket::write_frame_chunk`].
+    pub fn write_frame_chunk<Stream>(
+        &mut self,
+        stream: &mut Stream,
+        data: impl Into<Bytes>,
+    ) -> Result<()>
+    where
+        Stream: Read + Write,
+    {
+        self.write_fragment(stream, data.into(), false)
+    }
+
+    /// Send the final fragment of a message, see [`WebSocket::end_message`].
+    pub fn end_message<Stream>(&mut self, stream: &mut Stream) -> Result<()>
+    where
+        Stream: Read + Write,
+    {
+        self.write_fragment(stream, Bytes::new(), true)
+    }
+
+    fn write_fragment<Stream>(&mut self, stream: &mut Stream, data: Bytes, fin: bool) -> Result<()>
+    where
+        Stream: Read + Write,
+    {
+        // Taken out while writing, so the frame passes the check for a message in progress.
+        let opcode = self
+            .outgoing_fragment
+            .take()
+            .ok_or(Error::Protocol(ProtocolError::MessageNotStarted))?;
+        let result = self.write(stream, Message::Frame(Frame::message(data, opcode, fin)));
+        // I/O errors happen only after the frame has been buffered, any other error means
+        // the frame has been rejected and the message continues with the same opcode.
+        self.outgoing_fragment = match result {
+            Ok(()) | Err(Error::Io(_)) if fin => None,
+            Ok(()) | Err(Error::Io(_)) => Some(OpCode::Data(OpData::Continue)),
+            Err(_) => Some(opcode),
+        };
+        result
+    }
+
+    /// Check that a frame with the given opcode doesn't interleave with a fragmented message
+    /// being sent.
+    fn check_no_message_in_progress(&self, opcode: OpCode) -> Result<()> {
+        if self.outgoing_fragment.is_some() && matches!(opcode, OpCode::Data(_)) {
+            return Err(Error::Protocol(ProtocolError::MessageInProgress));
+        }
+        Ok(())
+    }
+
+    /// Prepare a message to be sent repeatedly, see [`WebSocket::prepare_frame`].
+    pub fn prepare_frame(&self, message: Message) -> Result<PreparedFrame> {
+        let frame = match message {
+            Message::Text(data) => Frame::message(data, OpCode::Data(OpData::Text), true),
+            Message::Binary(data) => Frame::message(data, OpCode::Data(OpData::Binary), true),
+            Message::Ping(data) => Frame::ping(data),
+            Message::Pong(data) => Frame::pong(data),
+            Message::Close(code) => {
+                check_close_code(code.as_ref())?;
+                Frame::close(code)
+            }
+            Message::Frame(f) => {
+                check_control_frame(&f)?;
+                check_reserved_bits(&f, self.config.extension_reserved_bits)?;
+                f
+            }
+        };
+        Ok(PreparedFrame::new(frame))
+    }
+
+    /// Write a prepared frame to the provided stream, if possible.
+    ///
+    /// See [`WebSocket::write_prepared`].
+    pub fn write_prepared<Stream>(
+        &mut self,
+        stream: &mut Stream,
+        frame: &PreparedFrame,
+    ) -> Result<()>
+    where
+        Stream: Read + Write,
+    {
+        self.state.check_not_terminated()?;
+
+        if !self.state.is_active() {
+            let message = Message::Frame(frame.frame().clone());
+            return Err(Error::Protocol(ProtocolError::SendAfterClosing(message)));
+        }
+        self.check_no_message_in_progress(frame.frame().header().opcode)?;
+
+        trace!("Sending prepared frame: {:?}", frame.frame());
+        let masked = self.needs_mask(frame.frame());
+        let result = self.frame.buffer_prepared_frame(stream, frame, masked);
+        if !matches!(result, Err(Error::WriteBufferFull(_))) {
+            self.unflushed_len += frame.len(masked);
+            if completes_message(frame.frame()) {
+                self.messages_written += 1;
+            }
+        }
+        result.check_connection_reset(self.state)?;
+
+        if frame.frame().header().opcode == OpCode::Control(OpCtl::Close) {
+            self.state = WebSocketState::ClosedByUs;
+            return self.flush(stream);
+        }
+
+        let should_flush = self._write(stream, None)?;
         if should_flush {
             self.flush(stream)?;
         }
@@ -517,11 +1621,34 @@ impl WebSocketContext {
     {
         self._write(stream, None)?;
         self.frame.write_out_buffer(stream)?;
+        if self.additional_send.is_some() {
+            // The additional frame didn't fit into the full write buffer,
+            // now that the buffer has been written out there is room for it.
+            self._write(stream, None)?;
+            self.frame.write_out_buffer(stream)?;
+        }
         stream.flush()?;
         self.unflushed_additional = false;
+        self.unflushed_len = 0;
         Ok(())
     }
 
+    /// Flush writes only if more than [`write_buffer_size`](WebSocketConfig::write_buffer_size)
+    /// bytes were queued since the last flush, see [`WebSocket::flush_if_full`].
+    ///
+    /// Returns `true` if it flushed like [`flush`](Self::flush) does.
+    pub fn flush_if_full<Stream>(&mut self, stream: &mut Stream) -> Result<bool>
+    where
+        Stream: Read + Write,
+    {
+        if self.unflushed_additional || self.unflushed_len > self.config.write_buffer_size {
+            self.flush(stream)?;
+            Ok(true)
+        } else {
+            Ok(false)
+        }
+    }
+
     /// Writes any data in the out_buffer, `additional_send` and given `data`.
     ///
     /// Does **not** flush.
@@ -564,6 +1691,12 @@ impl WebSocketContext {
             // server impact as a TIME_WAIT connection is immediately reopened upon
             // a new SYN with a higher seq number). (RFC 6455)
             self.frame.write_out_buffer(stream)?;
+            if let Some(reply) = self.additional_send.take() {
+                // The close reply didn't fit into the full write buffer before,
+                // it must still be sent before closing the connection.
+                self.buffer_frame(stream, reply)?;
+                self.frame.write_out_buffer(stream)?;
+            }
             self.state = WebSocketState::Terminated;
             Err(Error::ConnectionClosed)
         } else {
@@ -580,40 +1713,66 @@ impl WebSocketContext {
     where
         Stream: Read + Write,
     {
+        check_close_code(code.as_ref())?;
         if let WebSocketState::Active = self.state {
             self.state = WebSocketState::ClosedByUs;
             let frame = Frame::close(code);
-            self._write(stream, Some(frame))?;
+            match self._write(stream, Some(frame)) {
+                Err(Error::WriteBufferFull(Message::Frame(frame))) => {
+                    // The write buffer is at capacity. Keep the close frame as the pending
+                    // additional frame so it's written as soon as there is room for it.
+                    self.set_additional(frame);
+                }
+                Err(err) => return Err(err),
+                Ok(_) => {}
+            }
         }
         self.flush(stream)
     }
 
+    /// Note that the next message is received in a single frame of the given payload size.
+    fn set_single_frame_message(&mut self, size: usize) {
+        self.last_message_frame_count = 1;
+        self.last_message_frame_sizes.clear();
+        if self.config.record_frame_sizes {
+            self.last_message_frame_sizes.push(size);
+        }
+    }
+
     /// Try to decode one message frame. May return None.
-    fn read_message_frame(&mut self, stream: &mut impl Read) -> Result<Option<Message>> {
+    fn read_message_frame(&mut self, stream: &mut (impl Read + Write)) -> Result<Option<Message>> {
         if let Some(frame) = self
             .frame
             .read_frame(
                 stream,
                 self.config.max_frame_size,
                 matches!(self.role, Role::Server),
-                self.config.accept_unmasked_frames,
+                self.config.accept_unmasked_frames && !self.config.strict_masking,
             )
             .check_connection_reset(self.state)?
         {
             if !self.state.can_read() {
                 return Err(Error::Protocol(ProtocolError::ReceivedAfterClosing));
             }
+            if self.state == WebSocketState::ClosedByUs
+                && frame.header().opcode != OpCode::Control(OpCtl::Close)
+            {
+                self.received_after_close += frame.payload().len();
+                if self
+                    .config
+                    .max_data_after_close
+                    .map_or(false, |max| self.received_after_close > max)
+                {
+                    self.state = WebSocketState::Terminated;
+                    return Err(Error::Protocol(ProtocolError::TooMuchDataAfterClose));
+                }
+            }
             // MUST be 0 unless an extension is negotiated that defines meanings
             // for non-zero values.  If a nonzero value is received and none of
             // the negotiated extensions defines the meaning of such a nonzero
             // value, the receiving endpoint MUST _Fail the WebSocket
             // Connection_.
-            {
-                let hdr = frame.header();
-                if hdr.rsv1 || hdr.rsv2 || hdr.rsv3 {
-                    return Err(Error::Protocol(ProtocolError::NonZeroReservedBits));
-                }
-            }
+            check_reserved_bits(&frame, self.config.extension_reserved_bits)?;
 
             if self.role == Role::Client && frame.is_masked() {
                 // A client MUST close a connection if it detects a masked frame. (RFC 6455)
@@ -622,23 +1781,36 @@ impl WebSocketContext {
 
             match frame.header().opcode {
                 OpCode::Control(ctl) => {
+                    self.control_frames_received += 1;
+                    self.set_single_frame_message(frame.payload().len());
                     match ctl {
                         // All control frames MUST have a payload length of 125 bytes or less
                         // and MUST NOT be fragmented. (RFC 6455)
                         _ if !frame.header().is_final => {
                             Err(Error::Protocol(ProtocolError::FragmentedControlFrame))
                         }
+                        OpCtl::Close
+                            if frame.payload().len()
+                                > self.config.max_close_payload_size.unwrap_or(125) =>
+                        {
+                            Err(Error::Protocol(ProtocolError::ControlFrameTooBig))
+                        }
+                        OpCtl::Close => {
+                            let close = frame.into_close(self.config.lossy_close_reason)?;
+                            Ok(self.do_close(close).map(Message::Close))
+                        }
                         _ if frame.payload().len() > 125 => {
                             Err(Error::Protocol(ProtocolError::ControlFrameTooBig))
                         }
-                        OpCtl::Close => Ok(self.do_close(frame.into_close()?).map(Message::Close)),
                         OpCtl::Reserved(i) => {
                             Err(Error::Protocol(ProtocolError::UnknownControlFrameType(i)))
                         }
                         OpCtl::Ping => {
                             let data = frame.into_payload();
                             // No ping processing after we sent a close frame.
-                            if self.state.is_active() {
+                            if self.state.is_active()
+                                && (data.is_empty() || !self.config.auto_pong_only_empty)
+                            {
                                 self.set_additional(Frame::pong(data.clone()));
                             }
                             Ok(Some(Message::Ping(data)))
@@ -648,6 +1820,7 @@ impl WebSocketContext {
                 }
 
                 OpCode::Data(data) => {
+                    self.data_frames_received += 1;
                     let fin = frame.header().is_final;
                     match data {
                         OpData::Continue => {
@@ -659,7 +1832,12 @@ impl WebSocketContext {
                                 ));
                             }
                             if fin {
-                                Ok(Some(self.incomplete.take().unwrap().complete()?))
+                                let mut msg = self.incomplete.take().unwrap();
+                                self.last_message_frame_count = msg.frame_count();
+                                // Empty if the sizes weren't recorded from the first frame on.
+                                self.last_message_frame_sizes =
+                                    msg.take_frame_sizes().unwrap_or_default();
+                                Ok(Some(msg.complete()?))
                             } else {
                                 Ok(None)
                             }
@@ -669,19 +1847,38 @@ impl WebSocketContext {
                         }
                         OpData::Text if fin => {
                             check_max_size(frame.payload().len(), self.config.max_message_size)?;
-                            Ok(Some(Message::Text(frame.into_text()?)))
+                            self.set_single_frame_message(frame.payload().len());
+                            if self.config.invalid_text_as_binary {
+                                let payload = frame.into_payload();
+                                match Utf8Bytes::try_from(payload.clone()) {
+                                    Ok(text) => Ok(Some(Message::Text(text))),
+                                    Err(_) => {
+                                        debug!("Returning invalid UTF-8 text as binary");
+                                        Ok(Some(Message::Binary(payload)))
+                                    }
+                                }
+                            } else {
+                                Ok(Some(Message::Text(frame.into_text()?)))
+                            }
                         }
                         OpData::Binary if fin => {
                             check_max_size(frame.payload().len(), self.config.max_message_size)?;
+                            self.set_single_frame_message(frame.payload().len());
                             Ok(Some(Message::Binary(frame.into_payload())))
                         }
                         OpData::Text | OpData::Binary => {
                             let message_type = match data {
+                                OpData::Text if self.config.invalid_text_as_binary => {
+                                    IncompleteMessageType::RawText
+                                }
                                 OpData::Text => IncompleteMessageType::Text,
                                 OpData::Binary => IncompleteMessageType::Binary,
                                 _ => panic!("Bug: message is not text nor binary"),
                             };
                             let mut incomplete = IncompleteMessage::new(message_type);
+                            if self.config.record_frame_sizes {
+                                incomplete.record_frame_sizes();
+                            }
                             incomplete
                                 .extend(frame.into_payload(), self.config.max_message_size)?;
                             self.incomplete = Some(incomplete);
@@ -699,11 +1896,32 @@ impl WebSocketContext {
                 WebSocketState::ClosedByPeer | WebSocketState::CloseAcknowledged => {
                     Err(Error::ConnectionClosed)
                 }
-                _ => Err(Error::Protocol(ProtocolError::ResetWithoutClosingHandshake)),
+                state => {
+                    if let Some(on_abnormal_close) = &mut self.on_abnormal_close.0 {
+                        on_abnormal_close(self.control_frames_received + self.data_frames_received);
+                    }
+                    if self.config.close_on_abnormal_close && state == WebSocketState::Active {
+                        self.send_abnormal_close(stream);
+                    }
+                    Err(Error::Protocol(ProtocolError::ResetWithoutClosingHandshake))
+                }
             }
         }
     }
 
+    /// Try to send a close frame after the peer closed the connection without a closing
+    /// handshake. Errors are ignored, the connection is over anyway.
+    fn send_abnormal_close(&mut self, stream: &mut (impl Read + Write)) {
+        let close = CloseFrame { code: CloseCode::Protocol, reason: Default::default() };
+        let result = self
+            .buffer_frame(stream, Frame::close(Some(close)))
+            .and_then(|()| self.frame.write_out_buffer(stream))
+            .and_then(|()| Ok(stream.flush()?));
+        if let Err(err) = result {
+            debug!("Failed to send close frame after abnormal close: {err}");
+        }
+    }
+
     /// Received a close frame. Tells if we need to return a close frame to the user.
     #[allow(clippy::option_option)]
     fn do_close(&mut self, close: Option<CloseFrame>) -> Option<Option<CloseFrame>> {
@@ -712,8 +1930,11 @@ impl WebSocketContext {
             WebSocketState::Active => {
                 self.state = WebSocketState::ClosedByPeer;
 
+                let received =
+                    if self.config.keep_invalid_close_code { close.clone() } else { None };
                 let close = close.map(|frame| {
                     if !frame.code.is_allowed() {
+                        debug!("Received disallowed close code {}", u16::from(frame.code));
                         CloseFrame {
                             code: CloseCode::Protocol,
                             reason: Utf8Bytes::from_static("Protocol violation"),
@@ -723,11 +1944,24 @@ impl WebSocketContext {
                     }
                 });
 
-                let reply = Frame::close(close.clone());
+                let reply = self.close_reply.build(close.as_ref());
+
+                // The peer may have sent an oversized reason if we accept those,
+                // but our reply must still be a valid control frame.
+                let reply = Frame::close(reply.map(|mut frame| {
+                    if frame.reason.len() > 123 {
+                        let mut end = 123;
+                        while !frame.reason.is_char_boundary(end) {
+                            end -= 1;
+                        }
+                        frame.reason = frame.reason[..end].into();
+                    }
+                    frame
+                }));
                 debug!("Replying to close with {reply:?}");
                 self.set_additional(reply);
 
-                Some(close)
+                Some(if self.config.keep_invalid_close_code { received } else { close })
             }
             WebSocketState::ClosedByPeer | WebSocketState::CloseAcknowledged => {
                 // It is already closed, just ignore.
@@ -742,22 +1976,35 @@ impl WebSocketContext {
         }
     }
 
+    /// Whether `frame` has to be masked when sent by this endpoint.
+    fn needs_mask(&self, frame: &Frame) -> bool {
+        match self.role {
+            Role::Server => false,
+            // 5.  If the data is being sent by the client, the frame(s) MUST be
+            // masked as defined in Section 5.3. (RFC 6455)
+            Role::Client => {
+                let is_close = frame.header().opcode == OpCode::Control(OpCtl::Close);
+                !(is_close && self.config.unmasked_client_close)
+            }
+        }
+    }
+
     /// Write a single frame into the write-buffer.
     fn buffer_frame<Stream>(&mut self, stream: &mut Stream, mut frame: Frame) -> Result<()>
     where
         Stream: Read + Write,
     {
-        match self.role {
-            Role::Server => {}
-            Role::Client => {
-                // 5.  If the data is being sent by the client, the frame(s) MUST be
-                // masked as defined in Section 5.3. (RFC 6455)
-                frame.set_random_mask();
-            }
+        if self.needs_mask(&frame) {
+            frame.set_random_mask();
         }
 
         trace!("Sending frame: {frame:?}");
-        self.frame.buffer_frame(stream, frame).check_connection_reset(self.state)
+        let len = frame.len();
+        let result = self.frame.buffer_frame(stream, frame);
+        if !matches!(result, Err(Error::WriteBufferFull(_))) {
+            self.unflushed_len += len;
+        }
+        result.check_connection_reset(self.state)
     }
 
     /// Replace `additional_send` if it is currently a `Pong` message.
@@ -772,6 +2019,49 @@ impl WebSocketContext {
     }
 }
 
+/// Read up to `size` bytes from `reader`. Returns less data only on EOF.
+fn read_chunk(reader: &mut impl Read, size: usize) -> io::Result<Vec<u8>> {
+    let mut chunk = Vec::with_capacity(size);
+    reader.take(size as u64).read_to_end(&mut chunk)?;
+    Ok(chunk)
+}
+
+/// Whether sending `frame` completes a data message, i.e. it is its final frame.
+fn completes_message(frame: &Frame) -> bool {
+    matches!(frame.header().opcode, OpCode::Data(_)) && frame.header().is_final
+}
+
+/// Raw control frames must follow the same rules we enforce for incoming ones.
+fn check_control_frame(frame: &Frame) -> Result<()> {
+    if matches!(frame.header().opcode, OpCode::Control(_)) {
+        if !frame.header().is_final {
+            return Err(Error::Protocol(ProtocolError::FragmentedControlFrame));
+        }
+        if frame.payload().len() > 125 {
+            return Err(Error::Protocol(ProtocolError::ControlFrameTooBig));
+        }
+    }
+    Ok(())
+}
+
+/// Check that only the reserved bits defined by an extension are set.
+fn check_reserved_bits(frame: &Frame, extension_reserved_bits: u8) -> Result<()> {
+    if frame.header().reserved_bits() & !extension_reserved_bits != 0 {
+        return Err(Error::Protocol(ProtocolError::NonZeroReservedBits));
+    }
+    Ok(())
+}
+
+/// Check that a close code may be sent, codes like 1005 or 1006 are reserved for local use.
+fn check_close_code(close: Option<&CloseFrame>) -> Result<()> {
+    match close {
+        Some(close) if !close.code.is_allowed() => {
+            Err(Error::Protocol(ProtocolError::InvalidCloseCode(close.code.into())))
+        }
+        _ => Ok(()),
+    }
+}
+
 fn check_max_size(size: usize, max_size: Option<usize>) -> crate::Result<()> {
     if let Some(max_size) = max_size {
         if size > max_size {
@@ -818,6 +2108,53 @@ impl WebSocketState {
     }
 }
 
+/// A stream wrapper that never reads new data, used to only consume what is already buffered.
+/// Writes are passed through.
+struct BufferedOnly<'s, Stream>(&'s mut Stream);
+
+impl<Stream> Read for BufferedOnly<'_, Stream> {
+    fn read(&mut self, _: &mut [u8]) -> io::Result<usize> {
+        Err(io::ErrorKind::WouldBlock.into())
+    }
+}
+
+impl<Stream: Write> Write for BufferedOnly<'_, Stream> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.flush()
+    }
+}
+
+/// A stream wrapper keeping track of writes and flushes.
+struct CountingStream<'s, Stream> {
+    stream: &'s mut Stream,
+    written: usize,
+    flushed: bool,
+}
+
+impl<Stream: Read> Read for CountingStream<'_, Stream> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.stream.read(buf)
+    }
+}
+
+impl<Stream: Write> Write for CountingStream<'_, Stream> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let len = self.stream.write(buf)?;
+        self.written += len;
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.stream.flush()?;
+        self.flushed = true;
+        Ok(())
+    }
+}
+
 /// Translate "Connection reset by peer" into `ConnectionClosed` if appropriate.
 trait CheckConnectionReset {
     fn check_connection_reset(self, state: WebSocketState) -> Self;
@@ -840,10 +2177,24 @@ impl<T> CheckConnectionReset for Result<T> {
 
 #[cfg(test)]
 mod tests {
-    use super::{Message, Role, WebSocket, WebSocketConfig};
-    use crate::error::{CapacityError, Error};
-
-    use std::{io, io::Cursor};
+    use super::{
+        frame::{
+            coding::{CloseCode, Data, OpCode},
+            CloseFrame, Frame, FrameSocket,
+        },
+        BatchWrite, CloseReply, Limits, Message, Role, SharedConfig, WebSocket, WebSocketConfig,
+    };
+    use crate::error::{CapacityError, Error, ProtocolError};
+
+    use std::{
+        collections::VecDeque,
+        io,
+        io::Cursor,
+        sync::{
+            atomic::{AtomicU64, Ordering},
+            Arc,
+        },
+    };
 
     struct WriteMoc<Stream>(Stream);
 
@@ -862,6 +2213,243 @@ mod tests {
         }
     }
 
+    /// `Write` impl that would block while `blocked` is set.
+    struct BlockingWrite {
+        written: Vec<u8>,
+        blocked: bool,
+    }
+
+    impl io::Write for BlockingWrite {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            if self.blocked {
+                return Err(io::Error::new(io::ErrorKind::WouldBlock, "blocked"));
+            }
+            self.written.extend_from_slice(buf);
+            Ok(buf.len())
+        }
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl io::Read for BlockingWrite {
+        fn read(&mut self, _: &mut [u8]) -> io::Result<usize> {
+            Err(io::Error::new(io::ErrorKind::WouldBlock, "nothing to read"))
+        }
+    }
+
+    /// Stream recording everything written and counting flushes.
+    struct FlushCounter {
+        written: Vec<u8>,
+        flushes: usize,
+    }
+
+    impl io::Write for FlushCounter {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.written.extend_from_slice(buf);
+            Ok(buf.len())
+        }
+        fn flush(&mut self) -> io::Result<()> {
+            self.flushes += 1;
+            Ok(())
+        }
+    }
+
+    impl io::Read for FlushCounter {
+        fn read(&mut self, _: &mut [u8]) -> io::Result<usize> {
+            Err(io::Error::new(io::ErrorKind::WouldBlock, "nothing to read"))
+        }
+    }
+
+    /// Stream reading from a buffer and recording everything written.
+    struct RecordingStream {
+        incoming: Cursor<Vec<u8>>,
+        written: Vec<u8>,
+    }
+
+    impl io::Write for RecordingStream {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.written.extend_from_slice(buf);
+            Ok(buf.len())
+        }
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl io::Read for RecordingStream {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.incoming.read(buf)
+        }
+    }
+
+    /// Stream reading the given chunks, failing with `WouldBlock` for each empty chunk.
+    struct InterruptedStream {
+        chunks: VecDeque<Vec<u8>>,
+    }
+
+    impl io::Write for InterruptedStream {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            Ok(buf.len())
+        }
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl io::Read for InterruptedStream {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let chunk = match self.chunks.front_mut() {
+                Some(chunk) => chunk,
+                None => return Ok(0),
+            };
+            if chunk.is_empty() {
+                self.chunks.pop_front();
+                return Err(io::ErrorKind::WouldBlock.into());
+            }
+            let n = buf.len().min(chunk.len());
+            buf[..n].copy_from_slice(&chunk[..n]);
+            chunk.drain(..n);
+            if chunk.is_empty() {
+                self.chunks.pop_front();
+            }
+            Ok(n)
+        }
+    }
+
+    #[test]
+    fn read_resumes_after_would_block() {
+        let mut data = vec![
+            0x01, 0x02, b'h', b'e', // first fragment of a text message
+            0x89, 0x01, 0x07, // interleaved ping
+            0x80, 0x01, b'y', // final fragment
+            0x82, 0x7e, 0x00, 0x80, // binary message with a 16 bit length
+        ];
+        data.extend_from_slice(&[0xaa; 128]);
+        data.extend_from_slice(&[0x88, 0x02, 0x03, 0xe8]);
+        let expected = vec![
+            Message::Ping(vec![0x07].into()),
+            Message::Text("hey".into()),
+            Message::Binary(vec![0xaa; 128].into()),
+            Message::Close(Some(CloseFrame { code: CloseCode::Normal, reason: "".into() })),
+        ];
+
+        let read_all = |chunks: VecDeque<Vec<u8>>| {
+            let stream = InterruptedStream { chunks };
+            let mut socket = WebSocket::from_raw_socket(stream, Role::Client, None);
+            let mut messages = vec![];
+            while messages.len() < expected.len() {
+                match socket.read() {
+                    Ok(message) => messages.push(message),
+                    Err(Error::Io(e)) if e.kind() == io::ErrorKind::WouldBlock => {}
+                    Err(e) => panic!("unexpected error {e}"),
+                }
+            }
+            messages
+        };
+
+        // Interrupted once at every position, i.e. mid-header and mid-payload.
+        for split in 1..data.len() {
+            let chunks = [data[..split].to_vec(), vec![], data[split..].to_vec()].into();
+            assert_eq!(read_all(chunks), expected, "interrupted after {split} bytes");
+        }
+
+        // Interrupted after every single byte.
+        let chunks = data.iter().flat_map(|&b| [vec![b], vec![]]).collect();
+        assert_eq!(read_all(chunks), expected);
+    }
+
+    #[test]
+    fn second_fragmented_message() {
+        let incoming = Cursor::new(vec![
+            0x01, 0x01, b'a', // first fragment of a text message
+            0x89, 0x00, // interleaved ping
+            0x02, 0x01, 0x01, // start of a binary message
+        ]);
+        let config = WebSocketConfig::default().accept_unmasked_frames(true);
+        let mut socket = WebSocket::from_raw_socket(WriteMoc(incoming), Role::Server, Some(config));
+        assert_eq!(socket.read().unwrap(), Message::Ping(Default::default()));
+        let err = socket.read().unwrap_err();
+        assert!(matches!(err, Error::Protocol(ProtocolError::ExpectedFragment(Data::Binary))));
+        assert_eq!(err.to_string(), "WebSocket protocol error: While waiting for more fragments received a new BINARY frame");
+    }
+
+    #[test]
+    fn message_counters() {
+        let incoming = Cursor::new(vec![0x89, 0x00, 0x81, 0x02, b'h', b'i']);
+        let config = WebSocketConfig::default().accept_unmasked_frames(true);
+        let mut socket = WebSocket::from_raw_socket(WriteMoc(incoming), Role::Server, Some(config));
+        socket.read().unwrap();
+        socket.read().unwrap();
+        assert_eq!(socket.messages_read(), 2);
+
+        socket.send(Message::Text("hello".into())).unwrap();
+        socket.send(Message::Ping(Default::default())).unwrap();
+        socket.send(Message::Binary(vec![1].into())).unwrap();
+        assert_eq!(socket.messages_written(), 2);
+
+        socket.send_reader(Data::Binary, &[1u8, 2, 3][..], 2).unwrap();
+        assert_eq!(socket.messages_written(), 3);
+
+        let first = Frame::message(vec![1], OpCode::Data(Data::Binary), false);
+        let first = socket.prepare_frame(Message::Frame(first)).unwrap();
+        socket.write_prepared(&first).unwrap();
+        assert_eq!(socket.messages_written(), 3);
+        let last = Frame::message(vec![2], OpCode::Data(Data::Continue), true);
+        let last = socket.prepare_frame(Message::Frame(last)).unwrap();
+        socket.write_prepared(&last).unwrap();
+        assert_eq!(socket.messages_written(), 4);
+    }
+
+    #[test]
+    fn close_reply_with_full_write_buffer() {
+        struct Backpressure {
+            incoming: Cursor<Vec<u8>>,
+            written: Vec<u8>,
+            blocked: bool,
+        }
+
+        impl io::Write for Backpressure {
+            fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+                if self.blocked {
+                    return Err(io::Error::new(io::ErrorKind::WouldBlock, "blocked"));
+                }
+                self.written.extend_from_slice(buf);
+                Ok(buf.len())
+            }
+            fn flush(&mut self) -> io::Result<()> {
+                Ok(())
+            }
+        }
+
+        impl io::Read for Backpressure {
+            fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+                self.incoming.read(buf)
+            }
+        }
+
+        let stream = Backpressure {
+            incoming: Cursor::new(vec![0x88, 0x02, 0x03, 0xe8]),
+            written: vec![],
+            blocked: true,
+        };
+        let config = WebSocketConfig::default()
+            .write_buffer_size(0)
+            .max_write_buffer_size(8)
+            .accept_unmasked_frames(true);
+        let mut socket = WebSocket::from_raw_socket(stream, Role::Server, Some(config));
+
+        // Fill the write buffer entirely.
+        let err = socket.write(Message::Binary(vec![0; 6].into())).unwrap_err();
+        assert!(matches!(err, Error::Io(e) if e.kind() == io::ErrorKind::WouldBlock));
+        assert!(matches!(socket.read().unwrap(), Message::Close(Some(_))));
+
+        // The close reply is sent after the buffered message once the stream accepts data.
+        socket.get_mut().blocked = false;
+        assert!(matches!(socket.flush(), Err(Error::ConnectionClosed)));
+        assert_eq!(socket.get_ref().written[8..], [0x88, 0x02, 0x03, 0xe8]);
+    }
+
     #[test]
     fn receive_messages() {
         let incoming = Cursor::new(vec![
@@ -870,10 +2458,68 @@ mod tests {
             0x03,
         ]);
         let mut socket = WebSocket::from_raw_socket(WriteMoc(incoming), Role::Client, None);
+        assert_eq!(socket.last_message_frame_count(), 0);
+        assert_eq!(socket.control_frame_ratio(), 0.0);
         assert_eq!(socket.read().unwrap(), Message::Ping(vec![1, 2].into()));
         assert_eq!(socket.read().unwrap(), Message::Pong(vec![3].into()));
+        assert_eq!(socket.last_message_frame_count(), 1);
         assert_eq!(socket.read().unwrap(), Message::Text("Hello, World!".into()));
+        assert_eq!(socket.last_message_frame_count(), 2);
         assert_eq!(socket.read().unwrap(), Message::Binary(vec![0x01, 0x02, 0x03].into()));
+        assert_eq!(socket.last_message_frame_count(), 1);
+        assert_eq!(socket.control_frame_ratio(), 0.4);
+        assert!(socket.last_message_frame_sizes().is_empty());
+    }
+
+    #[test]
+    fn record_frame_sizes() {
+        let incoming = Cursor::new(vec![
+            0x02, 0x02, 0x01, 0x02, // first fragment
+            0x89, 0x01, 0x07, // interleaved ping
+            0x00, 0x00, // empty fragment
+            0x80, 0x01, 0x03, // final fragment
+            0x82, 0x01, 0x04,
+        ]);
+        let config = WebSocketConfig::default().record_frame_sizes(true);
+        let mut socket = WebSocket::from_raw_socket(WriteMoc(incoming), Role::Client, Some(config));
+        assert_eq!(socket.read().unwrap(), Message::Ping(vec![0x07].into()));
+        assert_eq!(socket.last_message_frame_sizes(), [1]);
+        assert_eq!(socket.read().unwrap(), Message::Binary(vec![1, 2, 3].into()));
+        assert_eq!(socket.last_message_frame_sizes(), [2, 0, 1]);
+        assert_eq!(socket.read().unwrap(), Message::Binary(vec![4].into()));
+        assert_eq!(socket.last_message_frame_sizes(), [1]);
+    }
+
+    #[test]
+    fn record_frame_sizes_toggled() {
+        let chunks = VecDeque::from(vec![
+            vec![0x82, 0x01, 0x01],
+            vec![0x82, 0x01, 0x02],
+            vec![0x82, 0x02, 0x01, 0x02],
+            vec![0x02, 0x01, 0x03], // first fragment
+            vec![],
+            vec![0x80, 0x01, 0x04], // final fragment
+        ]);
+        let config = WebSocketConfig::default().record_frame_sizes(true);
+        let mut socket =
+            WebSocket::from_raw_socket(InterruptedStream { chunks }, Role::Client, Some(config));
+        socket.read().unwrap();
+        assert_eq!(socket.last_message_frame_sizes(), [1]);
+
+        socket.set_config(|c| c.record_frame_sizes = false);
+        socket.read().unwrap();
+        assert!(socket.last_message_frame_sizes().is_empty());
+
+        socket.set_config(|c| c.record_frame_sizes = true);
+        socket.read().unwrap();
+        assert_eq!(socket.last_message_frame_sizes(), [2]);
+
+        // Turned on in the middle of a message, which can't report its sizes then.
+        socket.set_config(|c| c.record_frame_sizes = false);
+        assert!(matches!(socket.read(), Err(Error::Io(_))));
+        socket.set_config(|c| c.record_frame_sizes = true);
+        assert_eq!(socket.read().unwrap(), Message::Binary(vec![3, 4].into()));
+        assert!(socket.last_message_frame_sizes().is_empty());
     }
 
     #[test]
@@ -902,4 +2548,660 @@ mod tests {
             Err(Error::Capacity(CapacityError::MessageTooLong { size: 3, max_size: 2 }))
         ));
     }
+
+    #[test]
+    fn close_with_full_write_buffer() {
+        let stream = BlockingWrite { written: Vec::new(), blocked: true };
+        let config = WebSocketConfig::default().write_buffer_size(0).max_write_buffer_size(11);
+        let mut socket = WebSocket::from_raw_socket(stream, Role::Server, Some(config));
+
+        // The frame is buffered, but can't be written out.
+        let err = socket.write(Message::Binary(vec![0; 8].into())).unwrap_err();
+        assert!(matches!(err, Error::Io(e) if e.kind() == io::ErrorKind::WouldBlock));
+
+        // There is no room for the close frame, it must be queued nevertheless.
+        let err = socket.close(None).unwrap_err();
+        assert!(matches!(err, Error::Io(e) if e.kind() == io::ErrorKind::WouldBlock));
+        assert!(!socket.can_write());
+
+        socket.get_mut().blocked = false;
+        socket.flush().unwrap();
+        let written = &socket.get_ref().written;
+        assert_eq!(written.len(), 12);
+        assert_eq!(&written[10..], &[0x88, 0x00]);
+    }
+
+    #[test]
+    fn auto_pong_only_empty() {
+        let incoming = Cursor::new(vec![0x89, 0x02, 0x01, 0x02, 0x89, 0x00]);
+        let config = WebSocketConfig::default().auto_pong_only_empty(true);
+        let mut socket = WebSocket::from_raw_socket(WriteMoc(incoming), Role::Client, Some(config));
+
+        assert_eq!(socket.read().unwrap(), Message::Ping(vec![1, 2].into()));
+        assert!(socket.context.additional_send.is_none());
+
+        assert_eq!(socket.read().unwrap(), Message::Ping(<_>::default()));
+        assert!(socket.context.additional_send.is_some());
+    }
+
+    #[test]
+    fn effective_limits() {
+        let config = WebSocketConfig::default().max_message_size(Some(1024));
+        let mut socket =
+            WebSocket::from_raw_socket(Cursor::new(Vec::<u8>::new()), Role::Client, Some(config));
+        assert_eq!(
+            socket.effective_limits(),
+            Limits {
+                max_message_size: Some(1024),
+                max_frame_size: Some(16 << 20),
+                max_write_buffer_size: usize::MAX
+            }
+        );
+
+        socket.set_config(|c| c.max_frame_size = None);
+        assert_eq!(socket.effective_limits().max_frame_size, None);
+
+        socket.set_config(|c| *c = c.unlimited_message_size());
+        assert_eq!(socket.effective_limits().max_message_size, None);
+    }
+
+    #[test]
+    fn shared_config() {
+        let shared = SharedConfig::new(WebSocketConfig::default().max_message_size(Some(1024)));
+        let sockets: Vec<_> = (0..2)
+            .map(|_| {
+                WebSocket::from_raw_socket_with_shared_config(
+                    Cursor::new(Vec::<u8>::new()),
+                    Role::Server,
+                    &shared,
+                )
+            })
+            .collect();
+        for socket in &sockets {
+            assert_eq!(socket.get_config().max_message_size, Some(1024));
+            assert!(Arc::ptr_eq(&socket.context.config, &shared.0));
+        }
+
+        let mut socket = sockets.into_iter().next().unwrap();
+        socket.set_config(|c| c.max_message_size = None);
+        assert_eq!(socket.get_config().max_message_size, None);
+        assert!(!Arc::ptr_eq(&socket.context.config, &shared.0));
+        assert_eq!(shared.max_message_size, Some(1024));
+    }
+
+    #[test]
+    fn write_invalid_control_frame() {
+        let mut socket = WebSocket::from_raw_socket(Cursor::new(Vec::new()), Role::Server, None);
+
+        let too_big = Frame::ping(vec![0; 126]);
+        assert!(matches!(
+            socket.write(Message::Frame(too_big)),
+            Err(Error::Protocol(ProtocolError::ControlFrameTooBig))
+        ));
+
+        let mut fragmented = Frame::ping(vec![]);
+        fragmented.header_mut().is_final = false;
+        assert!(matches!(
+            socket.write(Message::Frame(fragmented)),
+            Err(Error::Protocol(ProtocolError::FragmentedControlFrame))
+        ));
+
+        socket.write(Message::Frame(Frame::ping(vec![0; 125]))).unwrap();
+        socket.flush().unwrap();
+        assert_eq!(socket.get_ref().get_ref().len(), 2 + 125);
+    }
+
+    #[test]
+    fn read_buffered() {
+        let incoming = Cursor::new(vec![
+            0x89, 0x02, 0x01, 0x02, 0x81, 0x06, 0x48, 0x65, 0x6c, 0x6c, 0x6f, 0x2c, 0x82, 0x03,
+            0x01, 0x02, 0x03, 0x81, 0x01,
+        ]);
+        let mut socket = WebSocket::from_raw_socket(
+            WriteMoc(incoming),
+            Role::Client,
+            Some(WebSocketConfig::default().read_buffer_size(32)),
+        );
+        assert_eq!(
+            socket.read_buffered().unwrap(),
+            vec![
+                Message::Ping(vec![1, 2].into()),
+                Message::Text("Hello,".into()),
+                Message::Binary(vec![0x01, 0x02, 0x03].into()),
+            ]
+        );
+    }
+
+    #[test]
+    fn read_buffered_error() {
+        // A text message, a binary message and a frame with an unknown opcode.
+        let incoming = Cursor::new(vec![
+            0x81, 0x02, b'h', b'i', 0x82, 0x01, 0x01, 0x83, 0x00, 0x81, 0x01, b'!',
+        ]);
+        let mut socket = WebSocket::from_raw_socket(WriteMoc(incoming), Role::Client, None);
+        assert_eq!(
+            socket.read_buffered().unwrap(),
+            vec![Message::Text("hi".into()), Message::Binary(vec![0x01].into())]
+        );
+        assert!(matches!(
+            socket.read_buffered(),
+            Err(Error::Protocol(ProtocolError::InvalidOpcode(3)))
+        ));
+    }
+
+    #[test]
+    fn data_after_close_limit() {
+        // Two binary frames with 3 bytes of payload each, then a close frame.
+        let incoming = Cursor::new(vec![
+            0x82, 0x03, 0x01, 0x02, 0x03, 0x82, 0x03, 0x04, 0x05, 0x06, 0x88, 0x00,
+        ]);
+        let config = WebSocketConfig::default().max_data_after_close(Some(4));
+        let mut socket = WebSocket::from_raw_socket(WriteMoc(incoming), Role::Client, Some(config));
+        socket.close(None).unwrap();
+
+        assert_eq!(socket.read().unwrap(), Message::Binary(vec![0x01, 0x02, 0x03].into()));
+        assert!(matches!(
+            socket.read(),
+            Err(Error::Protocol(ProtocolError::TooMuchDataAfterClose))
+        ));
+        assert!(matches!(socket.read(), Err(Error::AlreadyClosed)));
+    }
+
+    #[test]
+    fn oversized_close_payload() {
+        let reason = "x".repeat(200);
+        let mut incoming = vec![0x88, 0x7e, 0x00, 202, 0x03, 0xe8];
+        incoming.extend_from_slice(reason.as_bytes());
+
+        let mut socket =
+            WebSocket::from_raw_socket(WriteMoc(Cursor::new(incoming.clone())), Role::Client, None);
+        assert!(matches!(socket.read(), Err(Error::Protocol(ProtocolError::ControlFrameTooBig))));
+
+        let config = WebSocketConfig::default()
+            .max_close_payload_size(Some(256))
+            .accept_unmasked_frames(true);
+        let stream = RecordingStream { incoming: Cursor::new(incoming), written: vec![] };
+        let mut socket = WebSocket::from_raw_socket(stream, Role::Server, Some(config));
+        match socket.read().unwrap() {
+            Message::Close(Some(close)) => assert_eq!(close.reason, reason.as_str()),
+            msg => panic!("unexpected message {msg:?}"),
+        }
+
+        // The reply carries the truncated reason.
+        assert!(matches!(socket.flush(), Err(Error::ConnectionClosed)));
+        let written = &socket.get_ref().written;
+        assert_eq!(written.len(), 2 + 125);
+        assert_eq!(&written[4..], &reason.as_bytes()[..123]);
+    }
+
+    #[test]
+    fn control_write_buffer_size() {
+        let config =
+            WebSocketConfig::default().write_buffer_size(1024).control_write_buffer_size(Some(0));
+        let mut socket =
+            WebSocket::from_raw_socket(Cursor::new(Vec::new()), Role::Server, Some(config));
+
+        socket.write(Message::Binary(vec![1, 2].into())).unwrap();
+        assert!(socket.get_ref().get_ref().is_empty());
+
+        // The ping is written right away, along with the data buffered before it.
+        socket.write(Message::Ping(vec![3].into())).unwrap();
+        assert_eq!(socket.get_ref().get_ref(), &[0x82, 0x02, 0x01, 0x02, 0x89, 0x01, 0x03]);
+    }
+
+    #[test]
+    fn write_batch() {
+        let config = WebSocketConfig::default().write_buffer_size(8);
+        let mut socket =
+            WebSocket::from_raw_socket(Cursor::new(Vec::new()), Role::Server, Some(config));
+
+        let batch = socket
+            .write_batch(vec![Message::Binary(vec![1, 2].into()), Message::Binary(vec![3].into())])
+            .unwrap();
+        assert_eq!(
+            batch,
+            BatchWrite { messages: 2, bytes_written: 0, bytes_buffered: 7, flushed: false }
+        );
+
+        let batch = socket.write_batch(vec![Message::Text("hello".into())]).unwrap();
+        assert_eq!(
+            batch,
+            BatchWrite { messages: 1, bytes_written: 14, bytes_buffered: 0, flushed: false }
+        );
+    }
+
+    #[test]
+    fn close_reply() {
+        let incoming = Cursor::new(vec![0x88, 0x02, 0x03, 0xe8]);
+        let config = WebSocketConfig::default().accept_unmasked_frames(true);
+        let stream = RecordingStream { incoming, written: vec![] };
+        let mut socket = WebSocket::from_raw_socket(stream, Role::Server, Some(config));
+        socket.set_close_reply(CloseReply::Fixed(CloseFrame {
+            code: CloseCode::Away,
+            reason: "bye".into(),
+        }));
+
+        // The peer's close frame is still returned as is.
+        assert_eq!(
+            socket.read().unwrap(),
+            Message::Close(Some(CloseFrame { code: CloseCode::Normal, reason: "".into() }))
+        );
+        assert!(matches!(socket.flush(), Err(Error::ConnectionClosed)));
+        assert_eq!(socket.get_ref().written, [0x88, 0x05, 0x03, 0xe9, b'b', b'y', b'e']);
+    }
+
+    #[test]
+    fn close_reply_custom() {
+        let incoming = Cursor::new(vec![0x88, 0x02, 0x03, 0xe8]);
+        let config = WebSocketConfig::default().accept_unmasked_frames(true);
+        let stream = RecordingStream { incoming, written: vec![] };
+        let mut socket = WebSocket::from_raw_socket(stream, Role::Server, Some(config));
+        // The reply may depend on state of the connection.
+        let reason = String::from("session 7");
+        socket.set_close_reply(CloseReply::Custom(Arc::new(move |close| {
+            let code = close.map_or(CloseCode::Normal, |close| close.code);
+            Some(CloseFrame { code, reason: reason.clone().into() })
+        })));
+
+        assert!(socket.read().unwrap().is_close());
+        assert!(matches!(socket.flush(), Err(Error::ConnectionClosed)));
+        assert_eq!(socket.get_ref().written[..4], [0x88, 0x0b, 0x03, 0xe8]);
+        assert_eq!(&socket.get_ref().written[4..], b"session 7");
+    }
+
+    #[test]
+    fn write_prepared() {
+        let mut socket = WebSocket::from_raw_socket(Cursor::new(Vec::new()), Role::Client, None);
+        let ping = socket.prepare_frame(Message::Ping(vec![0x01, 0x02].into())).unwrap();
+        socket.write_prepared(&ping).unwrap();
+        socket.write_prepared(&ping).unwrap();
+        socket.flush().unwrap();
+
+        let written = socket.get_ref().get_ref();
+        assert_eq!(written.len(), 16);
+        assert_eq!(written[..2], [0x89, 0x82]);
+        assert_eq!(written[8..10], [0x89, 0x82]);
+
+        let close = socket.prepare_frame(Message::Close(None)).unwrap();
+        socket.write_prepared(&close).unwrap();
+        assert!(matches!(
+            socket.write_prepared(&ping),
+            Err(Error::Protocol(ProtocolError::SendAfterClosing(_)))
+        ));
+    }
+
+    #[test]
+    fn write_prepared_across_roles() {
+        let server = WebSocket::from_raw_socket(Cursor::new(Vec::new()), Role::Server, None);
+        let binary = server.prepare_frame(Message::binary(vec![0x01, 0x02])).unwrap();
+
+        let mut client = WebSocket::from_raw_socket(Cursor::new(Vec::new()), Role::Client, None);
+        client.write_prepared(&binary).unwrap();
+        client.flush().unwrap();
+        let written = client.get_ref().get_ref();
+        assert_eq!(written.len(), 8);
+        assert_eq!(written[..2], [0x82, 0x82]);
+
+        let binary = client.prepare_frame(Message::binary(vec![0x01, 0x02])).unwrap();
+        let mut server = WebSocket::from_raw_socket(Cursor::new(Vec::new()), Role::Server, None);
+        server.write_prepared(&binary).unwrap();
+        server.flush().unwrap();
+        assert_eq!(server.get_ref().get_ref(), &[0x82, 0x02, 0x01, 0x02]);
+    }
+
+    #[test]
+    fn invalid_text_as_binary() {
+        let incoming = Cursor::new(vec![
+            0x81, 0x02, 0xc3, 0x28, // invalid text
+            0x01, 0x01, 0xc3, 0x80, 0x01, 0xa9, // fragmented valid text "é"
+            0x01, 0x01, 0xff, 0x80, 0x01, 0x61, // fragmented invalid text
+        ]);
+        let config =
+            WebSocketConfig::default().invalid_text_as_binary(true).accept_unmasked_frames(true);
+        let mut socket = WebSocket::from_raw_socket(WriteMoc(incoming), Role::Server, Some(config));
+        assert_eq!(socket.read().unwrap(), Message::Binary(vec![0xc3, 0x28].into()));
+        assert_eq!(socket.read().unwrap(), Message::Text("é".into()));
+        assert_eq!(socket.read().unwrap(), Message::Binary(vec![0xff, 0x61].into()));
+    }
+
+    #[test]
+    fn strict_masking() {
+        let incoming = Cursor::new(vec![0x82, 0x01, 0x01]);
+        let config = WebSocketConfig::default().accept_unmasked_frames(true).strict_masking(true);
+        let mut socket = WebSocket::from_raw_socket(WriteMoc(incoming), Role::Server, Some(config));
+        assert!(matches!(
+            socket.read(),
+            Err(Error::Protocol(ProtocolError::UnmaskedFrameFromClient))
+        ));
+    }
+
+    #[test]
+    fn extension_reserved_bits() {
+        let incoming = Cursor::new(vec![0xa2, 0x01, 0x01, 0x92, 0x01, 0x02]);
+        let config =
+            WebSocketConfig::default().extension_reserved_bits(0x20).accept_unmasked_frames(true);
+        let mut socket = WebSocket::from_raw_socket(WriteMoc(incoming), Role::Server, Some(config));
+        assert_eq!(socket.read().unwrap(), Message::Binary(vec![0x01].into()));
+        assert!(matches!(socket.read(), Err(Error::Protocol(ProtocolError::NonZeroReservedBits))));
+
+        let mut socket =
+            WebSocket::from_raw_socket(Cursor::new(Vec::new()), Role::Server, Some(config));
+        let mut frame = Frame::message(vec![0x01], OpCode::Data(Data::Binary), true);
+        frame.header_mut().rsv2 = true;
+        socket.send(Message::Frame(frame.clone())).unwrap();
+        assert_eq!(socket.get_ref().get_ref(), &[0xa2, 0x01, 0x01]);
+
+        frame.header_mut().rsv1 = true;
+        assert!(matches!(
+            socket.send(Message::Frame(frame)),
+            Err(Error::Protocol(ProtocolError::NonZeroReservedBits))
+        ));
+    }
+
+    #[test]
+    fn unmasked_client_close() {
+        let config = WebSocketConfig::default().unmasked_client_close(true);
+        let mut socket =
+            WebSocket::from_raw_socket(Cursor::new(Vec::new()), Role::Client, Some(config));
+        socket.send(Message::Ping(vec![0x01].into())).unwrap();
+        socket.close(None).unwrap();
+
+        // The ping is still masked, the close frame is not.
+        let written = socket.get_ref().get_ref();
+        assert_eq!(written[..2], [0x89, 0x81]);
+        assert_eq!(written[7..], [0x88, 0x00]);
+    }
+
+    #[test]
+    fn pending_auto_frame() {
+        let incoming = Cursor::new(vec![0x89, 0x02, 0x01, 0x02]);
+        let config = WebSocketConfig::default().accept_unmasked_frames(true);
+        let mut socket = WebSocket::from_raw_socket(WriteMoc(incoming), Role::Server, Some(config));
+        assert!(socket.pending_auto_frame().is_none());
+
+        // Reading doesn't flush the stream, so the pong is still queued.
+        assert_eq!(socket.read().unwrap(), Message::Ping(vec![0x01, 0x02].into()));
+        assert_eq!(socket.pending_auto_frame(), Some(&Frame::pong(vec![0x01, 0x02])));
+
+        socket.flush().unwrap();
+        assert!(socket.pending_auto_frame().is_none());
+    }
+
+    #[test]
+    fn keep_invalid_close_code() {
+        // Close code 1005 must not be sent over the wire.
+        let incoming = vec![0x88, 0x02, 0x03, 0xed];
+        let config = WebSocketConfig::default().accept_unmasked_frames(true);
+
+        let stream = RecordingStream { incoming: Cursor::new(incoming.clone()), written: vec![] };
+        let mut socket = WebSocket::from_raw_socket(stream, Role::Server, Some(config));
+        assert_eq!(
+            socket.read().unwrap(),
+            Message::Close(Some(CloseFrame {
+                code: CloseCode::Protocol,
+                reason: "Protocol violation".into()
+            }))
+        );
+
+        let config = config.keep_invalid_close_code(true);
+        let stream = RecordingStream { incoming: Cursor::new(incoming), written: vec![] };
+        let mut socket = WebSocket::from_raw_socket(stream, Role::Server, Some(config));
+        assert_eq!(
+            socket.read().unwrap(),
+            Message::Close(Some(CloseFrame { code: CloseCode::Status, reason: "".into() }))
+        );
+        assert!(matches!(socket.flush(), Err(Error::ConnectionClosed)));
+        assert_eq!(&socket.get_ref().written[..4], [0x88, 0x14, 0x03, 0xea]);
+    }
+
+    #[test]
+    fn on_abnormal_close() {
+        let frames_received = Arc::new(AtomicU64::new(u64::MAX));
+        let incoming = Cursor::new(vec![0x89, 0x00, 0x81, 0x02, b'h', b'i']);
+        let config = WebSocketConfig::default().accept_unmasked_frames(true);
+        let mut socket = WebSocket::from_raw_socket(WriteMoc(incoming), Role::Server, Some(config));
+        let record = frames_received.clone();
+        socket
+            .set_on_abnormal_close(Box::new(move |frames| record.store(frames, Ordering::SeqCst)));
+
+        assert_eq!(socket.read().unwrap(), Message::Ping(Default::default()));
+        assert_eq!(socket.read().unwrap(), Message::Text("hi".into()));
+        let err = socket.read().unwrap_err();
+        assert!(matches!(err, Error::Protocol(ProtocolError::ResetWithoutClosingHandshake)));
+        assert_eq!(err.abnormal_close_code(), Some(CloseCode::Abnormal));
+        assert_eq!(frames_received.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn close_on_abnormal_close() {
+        let incoming = Cursor::new(vec![0x81, 0x02, b'h', b'i']);
+        let stream = RecordingStream { incoming, written: vec![] };
+        let config =
+            WebSocketConfig::default().close_on_abnormal_close(true).accept_unmasked_frames(true);
+        let mut socket = WebSocket::from_raw_socket(stream, Role::Server, Some(config));
+
+        assert_eq!(socket.read().unwrap(), Message::Text("hi".into()));
+        assert!(socket.get_ref().written.is_empty());
+        assert!(matches!(
+            socket.read(),
+            Err(Error::Protocol(ProtocolError::ResetWithoutClosingHandshake))
+        ));
+        assert_eq!(socket.get_ref().written, [0x88, 0x02, 0x03, 0xea]);
+
+        // Without the option nothing is sent.
+        let incoming = Cursor::new(vec![0x81, 0x02, b'h', b'i']);
+        let stream = RecordingStream { incoming, written: vec![] };
+        let config = WebSocketConfig::default().accept_unmasked_frames(true);
+        let mut socket = WebSocket::from_raw_socket(stream, Role::Server, Some(config));
+        socket.read().unwrap();
+        socket.read().unwrap_err();
+        assert!(socket.get_ref().written.is_empty());
+    }
+
+    #[test]
+    fn is_write_empty() {
+        let mut socket = WebSocket::from_raw_socket(
+            BlockingWrite { written: vec![], blocked: true },
+            Role::Server,
+            None,
+        );
+        assert!(socket.is_write_empty());
+
+        socket.write(Message::Binary(vec![1, 2, 3].into())).unwrap();
+        assert!(!socket.is_write_empty());
+        assert!(socket.flush().is_err());
+        assert!(!socket.is_write_empty());
+
+        socket.get_mut().blocked = false;
+        socket.flush().unwrap();
+        assert!(socket.is_write_empty());
+    }
+
+    #[test]
+    fn flush_if_full() {
+        let stream = FlushCounter { written: vec![], flushes: 0 };
+        let mut socket = WebSocket::from_raw_socket(stream, Role::Server, None);
+        let write_buffer_size = socket.get_config().write_buffer_size;
+
+        socket.write(Message::Binary(vec![0; write_buffer_size / 2].into())).unwrap();
+        assert!(!socket.flush_if_full().unwrap());
+        assert_eq!(socket.get_ref().flushes, 0);
+
+        // The write buffer is written to the stream once it exceeds `write_buffer_size`,
+        // the data written still has to be flushed.
+        socket.write(Message::Binary(vec![0; write_buffer_size / 2].into())).unwrap();
+        assert!(socket.get_ref().written.len() > write_buffer_size);
+        assert_eq!(socket.get_ref().flushes, 0);
+        assert!(socket.flush_if_full().unwrap());
+        assert_eq!(socket.get_ref().flushes, 1);
+        assert!(!socket.flush_if_full().unwrap());
+
+        // A pending automatic reply is flushed regardless of its size.
+        socket.write(Message::Binary(vec![1, 2, 3].into())).unwrap();
+        socket.context.unflushed_additional = true;
+        assert!(socket.flush_if_full().unwrap());
+        assert_eq!(socket.get_ref().flushes, 2);
+    }
+
+    #[test]
+    fn close_and_drain() {
+        let incoming = Cursor::new(vec![0x81, 0x02, b'h', b'i', 0x88, 0x02, 0x03, 0xe8]);
+        let config = WebSocketConfig::default().accept_unmasked_frames(true);
+        let stream = RecordingStream { incoming, written: vec![] };
+        let mut socket = WebSocket::from_raw_socket(stream, Role::Server, Some(config));
+
+        let peer_close = socket.close_and_drain(None).unwrap();
+        assert_eq!(peer_close, Some(CloseFrame { code: CloseCode::Normal, reason: "".into() }));
+        assert_eq!(socket.get_ref().written, [0x88, 0x00]);
+        assert!(matches!(socket.read(), Err(Error::AlreadyClosed)));
+    }
+
+    #[test]
+    fn close_with_reserved_code() {
+        let mut socket = WebSocket::from_raw_socket(Cursor::new(Vec::new()), Role::Client, None);
+
+        let close = CloseFrame { code: CloseCode::Abnormal, reason: "".into() };
+        assert!(matches!(
+            socket.close(Some(close.clone())),
+            Err(Error::Protocol(ProtocolError::InvalidCloseCode(1006)))
+        ));
+        assert!(matches!(
+            socket.prepare_frame(Message::Close(Some(close))),
+            Err(Error::Protocol(ProtocolError::InvalidCloseCode(1006)))
+        ));
+        let close = CloseFrame { code: CloseCode::Status, reason: "".into() };
+        assert!(matches!(
+            socket.send(Message::Close(Some(close))),
+            Err(Error::Protocol(ProtocolError::InvalidCloseCode(1005)))
+        ));
+        assert!(socket.can_write());
+        assert!(socket.get_ref().get_ref().is_empty());
+
+        socket.close(Some(CloseFrame { code: CloseCode::Away, reason: "".into() })).unwrap();
+        assert!(!socket.can_write());
+    }
+
+    #[test]
+    fn pending_messages() {
+        let pending = vec![Message::Text("one".into()), Message::Binary(vec![2].into())];
+        let mut socket = WebSocket::from_raw_socket_with_pending(
+            Cursor::new(Vec::new()),
+            Role::Server,
+            None,
+            pending,
+        )
+        .unwrap();
+        assert!(socket.get_ref().get_ref().is_empty());
+
+        socket.flush().unwrap();
+        assert_eq!(socket.get_ref().get_ref(), &[0x81, 0x03, b'o', b'n', b'e', 0x82, 0x01, 0x02]);
+    }
+
+    #[test]
+    fn fragmented_message() {
+        let mut socket = WebSocket::from_raw_socket(Cursor::new(Vec::new()), Role::Server, None);
+        assert!(matches!(
+            socket.write_frame_chunk(vec![1]),
+            Err(Error::Protocol(ProtocolError::MessageNotStarted))
+        ));
+
+        socket.begin_message(Data::Binary).unwrap();
+        assert!(matches!(
+            socket.begin_message(Data::Text),
+            Err(Error::Protocol(ProtocolError::MessageInProgress))
+        ));
+        socket.write_frame_chunk(vec![1, 2]).unwrap();
+        socket.write(Message::Ping(<_>::default())).unwrap();
+        assert!(matches!(
+            socket.write(Message::binary(vec![3])),
+            Err(Error::Protocol(ProtocolError::MessageInProgress))
+        ));
+        assert!(matches!(
+            socket.write(Message::Frame(Frame::message(
+                vec![9],
+                OpCode::Data(Data::Continue),
+                true
+            ))),
+            Err(Error::Protocol(ProtocolError::MessageInProgress))
+        ));
+        assert!(matches!(
+            socket.write(Message::Frame(Frame::message(vec![9], OpCode::Data(Data::Text), true))),
+            Err(Error::Protocol(ProtocolError::MessageInProgress))
+        ));
+        let prepared = socket.prepare_frame(Message::binary(vec![9])).unwrap();
+        assert!(matches!(
+            socket.write_prepared(&prepared),
+            Err(Error::Protocol(ProtocolError::MessageInProgress))
+        ));
+        assert!(matches!(
+            socket.send_reader(Data::Binary, &[9u8][..], 4),
+            Err(Error::Protocol(ProtocolError::MessageInProgress))
+        ));
+        let ping = socket.prepare_frame(Message::Ping(<_>::default())).unwrap();
+        socket.write_prepared(&ping).unwrap();
+        socket.write_frame_chunk(vec![3]).unwrap();
+        socket.end_message().unwrap();
+        assert_eq!(socket.messages_written(), 1);
+        assert!(matches!(
+            socket.end_message(),
+            Err(Error::Protocol(ProtocolError::MessageNotStarted))
+        ));
+        socket.send(Message::binary(vec![4])).unwrap();
+
+        assert_eq!(
+            socket.get_ref().get_ref(),
+            &[
+                0x02, 0x02, 0x01, 0x02, // first fragment
+                0x89, 0x00, // interleaved ping
+                0x89, 0x00, // interleaved prepared ping
+                0x00, 0x01, 0x03, // continuation
+                0x80, 0x00, // empty final fragment
+                0x82, 0x01, 0x04,
+            ]
+        );
+    }
+
+    #[test]
+    fn fragmented_message_write_errors() {
+        let config = WebSocketConfig::default().write_buffer_size(0).max_write_buffer_size(8);
+        let mut socket = WebSocket::from_raw_socket(
+            BlockingWrite { written: vec![], blocked: true },
+            Role::Server,
+            Some(config),
+        );
+
+        socket.begin_message(Data::Binary).unwrap();
+        // Buffered despite the error, so the message continues.
+        assert!(matches!(socket.write_frame_chunk(vec![1, 2]), Err(Error::Io(_))));
+        // Rejected, so the next chunk is still a continuation.
+        assert!(matches!(socket.write_frame_chunk(vec![3; 5]), Err(Error::WriteBufferFull(_))));
+
+        socket.get_mut().blocked = false;
+        socket.write_frame_chunk(vec![3]).unwrap();
+        socket.end_message().unwrap();
+        socket.flush().unwrap();
+        assert_eq!(
+            socket.get_ref().written,
+            [0x02, 0x02, 0x01, 0x02, 0x00, 0x01, 0x03, 0x80, 0x00]
+        );
+    }
+
+    #[test]
+    fn send_reader() {
+        let mut socket = WebSocket::from_raw_socket(Cursor::new(Vec::new()), Role::Server, None);
+        socket.send_reader(Data::Binary, &[1u8, 2, 3, 4, 5, 6, 7, 8, 9, 10][..], 4).unwrap();
+        socket.send_reader(Data::Text, &b""[..], 4).unwrap();
+
+        let written = socket.get_ref().get_ref().clone();
+        let mut frames = FrameSocket::new(Cursor::new(written));
+        let mut next = || {
+            let frame = frames.read(None).unwrap().unwrap();
+            (frame.header().opcode, frame.header().is_final, frame.into_payload())
+        };
+        assert_eq!(next(), (OpCode::Data(Data::Binary), false, vec![1, 2, 3, 4].into()));
+        assert_eq!(next(), (OpCode::Data(Data::Continue), false, vec![5, 6, 7, 8].into()));
+        assert_eq!(next(), (OpCode::Data(Data::Continue), false, vec![9, 10].into()));
+        assert_eq!(next(), (OpCode::Data(Data::Continue), true, <_>::default()));
+        assert_eq!(next(), (OpCode::Data(Data::Text), true, <_>::default()));
+    }
 }
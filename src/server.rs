@@ -3,13 +3,16 @@
 pub use crate::handshake::server::ServerHandshake;
 
 use crate::handshake::{
-    server::{Callback, NoCallback},
+    server::{Callback, NoCallback, RequireSubprotocol},
     HandshakeError,
 };
 
 use crate::protocol::{WebSocket, WebSocketConfig};
 
-use std::io::{Read, Write};
+use std::{
+    io::{Read, Write},
+    net::{SocketAddr, TcpStream},
+};
 
 /// Accept the given Stream as a WebSocket.
 ///
@@ -39,6 +42,36 @@ pub fn accept<S: Read + Write>(
     accept_with_config(stream, None)
 }
 
+/// Accept the given [`TcpStream`] as a WebSocket, also returning the address of the client.
+///
+/// The address is captured before the handshake, so it is available for logging even if the
+/// stream is later wrapped. Apart from that it works exactly like [`accept_with_config`].
+#[allow(clippy::type_complexity)]
+pub fn accept_with_addr(
+    stream: TcpStream,
+    config: Option<WebSocketConfig>,
+) -> Result<
+    (WebSocket<TcpStream>, SocketAddr),
+    HandshakeError<ServerHandshake<TcpStream, NoCallback>>,
+> {
+    let addr = stream.peer_addr().map_err(|e| HandshakeError::Failure(e.into()))?;
+    accept_with_config(stream, config).map(|socket| (socket, addr))
+}
+
+/// Accept the given Stream as a WebSocket speaking the given subprotocol.
+///
+/// The subprotocol is selected in the `Sec-WebSocket-Protocol` response header if the client
+/// offered it. Otherwise the handshake is rejected with `400 Bad Request` and
+/// [`Error::Http`](crate::Error::Http) is returned. Apart from that it works exactly like
+/// [`accept_with_config`].
+pub fn accept_with_subprotocol<S: Read + Write>(
+    stream: S,
+    subprotocol: &str,
+    config: Option<WebSocketConfig>,
+) -> Result<WebSocket<S>, HandshakeError<ServerHandshake<S, RequireSubprotocol>>> {
+    accept_hdr_with_config(stream, RequireSubprotocol(subprotocol.to_owned()), config)
+}
+
 /// Accept the given Stream as a WebSocket.
 ///
 /// Uses a configuration provided as an argument. Calling it with `None` will use the default one
@@ -11,15 +11,26 @@ use std::{
     io::{Read, Result as IoResult, Write},
 };
 
-use std::net::TcpStream;
+#[cfg(feature = "testing")]
+use std::{
+    collections::VecDeque,
+    io::{Error as IoError, ErrorKind as IoErrorKind},
+    sync::{Arc, Mutex},
+};
+use std::{
+    net::{SocketAddr, TcpStream},
+    time::Duration,
+};
 
+#[cfg(feature = "testing")]
+use crate::protocol::{Role, WebSocket, WebSocketConfig};
 #[cfg(feature = "native-tls")]
 use native_tls_crate::TlsStream;
 #[cfg(feature = "__rustls-tls")]
 use rustls::StreamOwned;
 
 /// Stream mode, either plain TCP or TLS.
-#[derive(Clone, Copy, Debug)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum Mode {
     /// Plain mode (`ws://` URL).
     Plain,
@@ -99,6 +110,32 @@ impl<S: Read + Write + Debug> Debug for MaybeTlsStream<S> {
     }
 }
 
+impl<S: Read + Write> MaybeTlsStream<S> {
+    /// Get the mode of the stream, i.e. whether the connection is actually encrypted.
+    pub fn mode(&self) -> Mode {
+        match self {
+            MaybeTlsStream::Plain(_) => Mode::Plain,
+            #[cfg(feature = "native-tls")]
+            MaybeTlsStream::NativeTls(_) => Mode::Tls,
+            #[cfg(feature = "__rustls-tls")]
+            MaybeTlsStream::Rustls(_) => Mode::Tls,
+        }
+    }
+
+    /// Get the protocol selected by the server via ALPN, e.g. `b"http/1.1"`.
+    ///
+    /// Returns `None` if no protocol was negotiated, which is always the case for plain streams.
+    pub fn alpn_protocol(&self) -> Option<Vec<u8>> {
+        match self {
+            MaybeTlsStream::Plain(_) => None,
+            #[cfg(feature = "native-tls")]
+            MaybeTlsStream::NativeTls(s) => s.negotiated_alpn().ok().flatten(),
+            #[cfg(feature = "__rustls-tls")]
+            MaybeTlsStream::Rustls(s) => s.conn.alpn_protocol().map(<[u8]>::to_vec),
+        }
+    }
+}
+
 impl<S: Read + Write> Read for MaybeTlsStream<S> {
     fn read(&mut self, buf: &mut [u8]) -> IoResult<usize> {
         match *self {
@@ -144,3 +181,183 @@ impl<S: Read + Write + NoDelay> NoDelay for MaybeTlsStream<S> {
         }
     }
 }
+
+impl MaybeTlsStream<TcpStream> {
+    /// Get back the underlying [`TcpStream`] if the connection is not encrypted.
+    ///
+    /// Returns the stream unchanged as error if it is a TLS stream.
+    #[allow(unreachable_patterns)]
+    pub fn into_tcp(self) -> Result<TcpStream, Self> {
+        match self {
+            MaybeTlsStream::Plain(stream) => Ok(stream),
+            other => Err(other),
+        }
+    }
+
+    /// Get a reference to the underlying [`TcpStream`], also for TLS streams.
+    pub fn tcp_ref(&self) -> &TcpStream {
+        match self {
+            MaybeTlsStream::Plain(s) => s,
+            #[cfg(feature = "native-tls")]
+            MaybeTlsStream::NativeTls(s) => s.get_ref(),
+            #[cfg(feature = "__rustls-tls")]
+            MaybeTlsStream::Rustls(s) => s.get_ref(),
+        }
+    }
+
+    /// Get the address of the remote peer of the underlying [`TcpStream`].
+    ///
+    /// See [`TcpStream::peer_addr`].
+    pub fn peer_addr(&self) -> IoResult<SocketAddr> {
+        self.tcp_ref().peer_addr()
+    }
+
+    /// Set the read timeout of the underlying [`TcpStream`].
+    ///
+    /// See [`TcpStream::set_read_timeout`].
+    pub fn set_read_timeout(&self, dur: Option<Duration>) -> IoResult<()> {
+        self.tcp_ref().set_read_timeout(dur)
+    }
+
+    /// Set the write timeout of the underlying [`TcpStream`].
+    ///
+    /// See [`TcpStream::set_write_timeout`].
+    pub fn set_write_timeout(&self, dur: Option<Duration>) -> IoResult<()> {
+        self.tcp_ref().set_write_timeout(dur)
+    }
+
+    /// Set the `TCP_QUICKACK` option of the underlying [`TcpStream`].
+    ///
+    /// Enabling it disables delayed ACKs, which can reduce the latency of request/response
+    /// traffic. This option is only available on Linux and Android, where it is not permanent:
+    /// the kernel may switch back to delayed ACKs, so it may need to be set repeatedly, e.g.
+    /// after each read. Use it together with [`set_nodelay`](NoDelay::set_nodelay).
+    #[cfg(all(feature = "socket2", any(target_os = "linux", target_os = "android")))]
+    pub fn set_quickack(&self, quickack: bool) -> IoResult<()> {
+        socket2::SockRef::from(self.tcp_ref()).set_quickack(quickack)
+    }
+}
+
+/// Create a pair of connected in-memory streams.
+///
+/// Data written to one of the streams can be read from the other one. This is
+/// meant for tests, e.g. to run both the client and the server end of a WebSocket
+/// within one process.
+///
+/// The streams never block: reading from a stream with no data available returns
+/// a `WouldBlock` error. Once one of the streams is dropped the other one reads
+/// the remaining data followed by EOF and fails to write with `BrokenPipe`.
+#[cfg(feature = "testing")]
+pub fn duplex() -> (DuplexStream, DuplexStream) {
+    let a = Arc::new(Mutex::new(Pipe::default()));
+    let b = Arc::new(Mutex::new(Pipe::default()));
+    (DuplexStream { read: a.clone(), write: b.clone() }, DuplexStream { read: b, write: a })
+}
+
+/// Create a client and a server [`WebSocket`] connected over an in-memory [`duplex`] stream.
+///
+/// Both WebSockets are already past the handshake, see [`WebSocket::from_raw_socket`].
+/// The first one is the client, the second one the server.
+#[cfg(feature = "testing")]
+pub fn duplex_websockets(
+    config: Option<WebSocketConfig>,
+) -> (WebSocket<DuplexStream>, WebSocket<DuplexStream>) {
+    let (client, server) = duplex();
+    (
+        WebSocket::from_raw_socket(client, Role::Client, config),
+        WebSocket::from_raw_socket(server, Role::Server, config),
+    )
+}
+
+/// One end of an in-memory duplex stream, see [`duplex`].
+#[cfg(feature = "testing")]
+#[derive(Debug)]
+pub struct DuplexStream {
+    read: Arc<Mutex<Pipe>>,
+    write: Arc<Mutex<Pipe>>,
+}
+
+/// One direction of a [`DuplexStream`].
+#[cfg(feature = "testing")]
+#[derive(Debug, Default)]
+struct Pipe {
+    data: VecDeque<u8>,
+    /// One of the ends has been dropped.
+    closed: bool,
+}
+
+#[cfg(feature = "testing")]
+impl Read for DuplexStream {
+    fn read(&mut self, buf: &mut [u8]) -> IoResult<usize> {
+        let mut pipe = self.read.lock().expect("Bug: poisoned duplex stream");
+        if pipe.data.is_empty() && !buf.is_empty() && !pipe.closed {
+            return Err(IoError::new(IoErrorKind::WouldBlock, "no data available"));
+        }
+        pipe.data.read(buf)
+    }
+}
+
+#[cfg(feature = "testing")]
+impl Write for DuplexStream {
+    fn write(&mut self, buf: &[u8]) -> IoResult<usize> {
+        let mut pipe = self.write.lock().expect("Bug: poisoned duplex stream");
+        if pipe.closed {
+            return Err(IoError::new(IoErrorKind::BrokenPipe, "duplex stream closed"));
+        }
+        pipe.data.extend(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> IoResult<()> {
+        Ok(())
+    }
+}
+
+#[cfg(feature = "testing")]
+impl Drop for DuplexStream {
+    fn drop(&mut self) {
+        for pipe in [&self.read, &self.write] {
+            if let Ok(mut pipe) = pipe.lock() {
+                pipe.closed = true;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{MaybeTlsStream, Mode};
+    use std::{
+        net::{TcpListener, TcpStream},
+        time::Duration,
+    };
+
+    #[test]
+    fn plain_into_tcp() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let stream = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let local_addr = stream.local_addr().unwrap();
+
+        let stream = MaybeTlsStream::Plain(stream);
+        assert_eq!(stream.mode(), Mode::Plain);
+        assert_eq!(stream.peer_addr().unwrap(), listener.local_addr().unwrap());
+        assert_eq!(stream.alpn_protocol(), None);
+        stream.set_read_timeout(Some(Duration::from_secs(1))).unwrap();
+        stream.set_write_timeout(Some(Duration::from_secs(2))).unwrap();
+        let stream = stream.into_tcp().unwrap();
+        assert_eq!(stream.local_addr().unwrap(), local_addr);
+        assert_eq!(stream.read_timeout().unwrap(), Some(Duration::from_secs(1)));
+        assert_eq!(stream.write_timeout().unwrap(), Some(Duration::from_secs(2)));
+    }
+
+    #[test]
+    #[cfg(all(feature = "socket2", any(target_os = "linux", target_os = "android")))]
+    fn plain_quickack() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let stream = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+
+        let stream = MaybeTlsStream::Plain(stream);
+        stream.set_quickack(true).unwrap();
+        assert!(socket2::SockRef::from(stream.tcp_ref()).quickack().unwrap());
+    }
+}
@@ -1,10 +1,10 @@
 //! Connection helper.
-use std::io::{Read, Write};
+use std::io::{self, Read, Write};
 
 use crate::{
     client::{client_with_config, uri_mode, IntoClientRequest},
     error::UrlError,
-    handshake::client::Response,
+    handshake::client::{Request, Response},
     protocol::WebSocketConfig,
     stream::MaybeTlsStream,
     ClientHandshake, Error, HandshakeError, Result, WebSocket,
@@ -26,6 +26,33 @@ pub enum Connector {
     Rustls(std::sync::Arc<rustls::ClientConfig>),
 }
 
+impl Connector {
+    /// Create a connector which accepts any server certificate.
+    ///
+    /// # Danger
+    /// **Certificates, including the host name, are not verified at all**, so the connection is
+    /// open to man-in-the-middle attacks. This is only meant for tests against servers with
+    /// self-signed certificates and requires the `dangerous-accept-invalid-certs` feature.
+    ///
+    /// `native-tls` is used if enabled, `rustls` otherwise.
+    #[cfg(feature = "dangerous-accept-invalid-certs")]
+    pub fn insecure_accept_invalid_certs() -> Result<Self> {
+        #[cfg(feature = "native-tls")]
+        {
+            let connector = native_tls_crate::TlsConnector::builder()
+                .danger_accept_invalid_certs(true)
+                .danger_accept_invalid_hostnames(true)
+                .build()
+                .map_err(crate::error::TlsError::Native)?;
+            Ok(Connector::NativeTls(connector))
+        }
+        #[cfg(all(feature = "__rustls-tls", not(feature = "native-tls")))]
+        {
+            Ok(Connector::Rustls(self::encryption::rustls::insecure_config()))
+        }
+    }
+}
+
 mod encryption {
     #[cfg(feature = "native-tls")]
     pub mod native_tls {
@@ -44,6 +71,7 @@ mod encryption {
             domain: &str,
             mode: Mode,
             tls_connector: Option<TlsConnector>,
+            mut wait: impl FnMut(&S) -> Result<()>,
         ) -> Result<MaybeTlsStream<S>>
         where
             S: Read + Write,
@@ -53,15 +81,16 @@ mod encryption {
                 Mode::Tls => {
                     let try_connector = tls_connector.map_or_else(TlsConnector::new, Ok);
                     let connector = try_connector.map_err(TlsError::Native)?;
-                    let connected = connector.connect(domain, socket);
-                    match connected {
-                        Err(e) => match e {
-                            TlsHandshakeError::Failure(f) => Err(Error::Tls(f.into())),
-                            TlsHandshakeError::WouldBlock(_) => {
-                                panic!("Bug: TLS handshake not blocked")
+                    let mut connected = connector.connect(domain, socket);
+                    loop {
+                        match connected {
+                            Ok(s) => return Ok(MaybeTlsStream::NativeTls(s)),
+                            Err(TlsHandshakeError::Failure(f)) => return Err(Error::Tls(f.into())),
+                            Err(TlsHandshakeError::WouldBlock(mid)) => {
+                                wait(mid.get_ref())?;
+                                connected = mid.handshake();
                             }
-                        },
-                        Ok(s) => Ok(MaybeTlsStream::NativeTls(s)),
+                        }
                     }
                 }
             }
@@ -75,7 +104,7 @@ mod encryption {
 
         use std::{
             io::{Read, Write},
-            sync::Arc,
+            sync::{Arc, Mutex},
         };
 
         use crate::{
@@ -84,6 +113,124 @@ mod encryption {
             Result,
         };
 
+        /// Verifier accepting any server certificate.
+        #[cfg(all(feature = "dangerous-accept-invalid-certs", not(feature = "native-tls")))]
+        #[derive(Debug)]
+        struct NoCertificateVerification;
+
+        #[cfg(all(feature = "dangerous-accept-invalid-certs", not(feature = "native-tls")))]
+        impl rustls::client::danger::ServerCertVerifier for NoCertificateVerification {
+            fn verify_server_cert(
+                &self,
+                _end_entity: &rustls_pki_types::CertificateDer<'_>,
+                _intermediates: &[rustls_pki_types::CertificateDer<'_>],
+                _server_name: &ServerName<'_>,
+                _ocsp_response: &[u8],
+                _now: rustls_pki_types::UnixTime,
+            ) -> Result<rustls::client::danger::ServerCertVerified, rustls::Error> {
+                Ok(rustls::client::danger::ServerCertVerified::assertion())
+            }
+
+            fn verify_tls12_signature(
+                &self,
+                _message: &[u8],
+                _cert: &rustls_pki_types::CertificateDer<'_>,
+                _dss: &rustls::DigitallySignedStruct,
+            ) -> Result<rustls::client::danger::HandshakeSignatureValid, rustls::Error>
+            {
+                Ok(rustls::client::danger::HandshakeSignatureValid::assertion())
+            }
+
+            fn verify_tls13_signature(
+                &self,
+                _message: &[u8],
+                _cert: &rustls_pki_types::CertificateDer<'_>,
+                _dss: &rustls::DigitallySignedStruct,
+            ) -> Result<rustls::client::danger::HandshakeSignatureValid, rustls::Error>
+            {
+                Ok(rustls::client::danger::HandshakeSignatureValid::assertion())
+            }
+
+            fn supported_verify_schemes(&self) -> Vec<rustls::SignatureScheme> {
+                use rustls::SignatureScheme::*;
+                vec![
+                    RSA_PKCS1_SHA256,
+                    RSA_PKCS1_SHA384,
+                    RSA_PKCS1_SHA512,
+                    ECDSA_NISTP256_SHA256,
+                    ECDSA_NISTP384_SHA384,
+                    ECDSA_NISTP521_SHA512,
+                    RSA_PSS_SHA256,
+                    RSA_PSS_SHA384,
+                    RSA_PSS_SHA512,
+                    ED25519,
+                    ED448,
+                ]
+            }
+        }
+
+        /// Build a client config which doesn't verify server certificates.
+        #[cfg(all(feature = "dangerous-accept-invalid-certs", not(feature = "native-tls")))]
+        pub fn insecure_config() -> Arc<ClientConfig> {
+            let mut config = ClientConfig::builder()
+                .dangerous()
+                .with_custom_certificate_verifier(Arc::new(NoCertificateVerification))
+                .with_no_client_auth();
+            config.alpn_protocols = vec![b"http/1.1".to_vec()];
+            Arc::new(config)
+        }
+
+        /// The client config used when no connector is given, built on first use.
+        static DEFAULT_CONFIG: Mutex<Option<Arc<ClientConfig>>> = Mutex::new(None);
+
+        /// Get the default client config, loading the root certificates only once per process.
+        pub fn default_config() -> Result<Arc<ClientConfig>> {
+            let mut default_config = DEFAULT_CONFIG.lock().unwrap_or_else(|e| e.into_inner());
+            if let Some(config) = &*default_config {
+                return Ok(config.clone());
+            }
+
+            #[allow(unused_mut)]
+            let mut root_store = RootCertStore::empty();
+
+            #[cfg(feature = "rustls-tls-native-roots")]
+            {
+                let rustls_native_certs::CertificateResult { certs, errors, .. } =
+                    rustls_native_certs::load_native_certs();
+
+                if !errors.is_empty() {
+                    log::warn!("native root CA certificate loading errors: {errors:?}");
+                }
+
+                // Not finding any native root CA certificates is not fatal if the
+                // "rustls-tls-webpki-roots" feature is enabled.
+                #[cfg(not(feature = "rustls-tls-webpki-roots"))]
+                if certs.is_empty() {
+                    return Err(std::io::Error::new(
+                        std::io::ErrorKind::NotFound,
+                        format!("no native root CA certificates found (errors: {errors:?})"),
+                    )
+                    .into());
+                }
+
+                let total_number = certs.len();
+                let (number_added, number_ignored) = root_store.add_parsable_certificates(certs);
+                log::debug!("Added {number_added}/{total_number} native root certificates (ignored {number_ignored})");
+            }
+            #[cfg(feature = "rustls-tls-webpki-roots")]
+            {
+                root_store.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned());
+            }
+
+            let mut config =
+                ClientConfig::builder().with_root_certificates(root_store).with_no_client_auth();
+            // Some servers insist on the client advertising HTTP/1.1 before upgrading.
+            config.alpn_protocols = vec![b"http/1.1".to_vec()];
+            let config = Arc::new(config);
+            *default_config = Some(config.clone());
+            Ok(config)
+        }
+
         pub fn wrap_stream<S>(
             socket: S,
             domain: &str,
@@ -98,45 +245,7 @@ mod encryption {
                 Mode::Tls => {
                     let config = match tls_connector {
                         Some(config) => config,
-                        None => {
-                            #[allow(unused_mut)]
-                            let mut root_store = RootCertStore::empty();
-
-                            #[cfg(feature = "rustls-tls-native-roots")]
-                            {
-                                let rustls_native_certs::CertificateResult {
-                                    certs, errors, ..
-                                } = rustls_native_certs::load_native_certs();
-
-                                if !errors.is_empty() {
-                                    log::warn!(
-                                        "native root CA certificate loading errors: {errors:?}"
-                                    );
-                                }
-
-                                // Not finding any native root CA certificates is not fatal if the
-                                // "rustls-tls-webpki-roots" feature is enabled.
-                                #[cfg(not(feature = "rustls-tls-webpki-roots"))]
-                                if certs.is_empty() {
-                                    return Err(std::io::Error::new(std::io::ErrorKind::NotFound, format!("no native root CA certificates found (errors: {errors:?})")).into());
-                                }
-
-                                let total_number = certs.len();
-                                let (number_added, number_ignored) =
-                                    root_store.add_parsable_certificates(certs);
-                                log::debug!("Added {number_added}/{total_number} native root certificates (ignored {number_ignored})");
-                            }
-                            #[cfg(feature = "rustls-tls-webpki-roots")]
-                            {
-                                root_store.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned());
-                            }
-
-                            Arc::new(
-                                ClientConfig::builder()
-                                    .with_root_certificates(root_store)
-                                    .with_no_client_auth(),
-                            )
-                        }
+                        None => default_config()?,
                     };
                     let domain = ServerName::try_from(domain)
                         .map_err(|_| TlsError::InvalidDnsName)?
@@ -171,6 +280,23 @@ mod encryption {
     }
 }
 
+/// Get the `rustls` client config used when no [`Connector`] is given.
+///
+/// The config trusts the root certificates enabled by the `rustls-tls-*-roots` features and
+/// advertises `http/1.1` via ALPN. It is built once and shared by all connections. To change
+/// e.g. the ALPN protocols, clone it and pass the modified config as [`Connector::Rustls`]:
+///
+/// ```no_run
+/// # use std::sync::Arc;
+/// let mut config = (*tungstenite::default_rustls_config().unwrap()).clone();
+/// config.alpn_protocols.clear();
+/// let connector = tungstenite::Connector::Rustls(Arc::new(config));
+/// ```
+#[cfg(feature = "__rustls-tls")]
+pub fn default_rustls_config() -> Result<std::sync::Arc<rustls::ClientConfig>> {
+    self::encryption::rustls::default_config()
+}
+
 type TlsHandshakeError<S> = HandshakeError<ClientHandshake<MaybeTlsStream<S>>>;
 
 /// Creates a WebSocket handshake from a request and a stream,
@@ -202,7 +328,30 @@ where
     S: Read + Write,
 {
     let request = request.into_client_request()?;
+    let stream = wrap_client_stream(&request, stream, connector, would_block)?;
+    client_with_config(request, stream, config)
+}
+
+/// Fail a TLS handshake which can't continue on a blocking stream, e.g. because a read
+/// timeout of the stream elapsed.
+fn would_block<S>(_: &S) -> Result<()> {
+    Err(Error::Io(io::Error::new(io::ErrorKind::WouldBlock, "TLS handshake would block")))
+}
 
+/// Upgrade the stream to TLS if the request URI requires it.
+///
+/// If the TLS handshake would block, `wait` is called before trying again. It is only used by
+/// `native-tls`, which does the handshake while wrapping the stream.
+#[cfg_attr(not(feature = "native-tls"), allow(unused_variables))]
+pub(crate) fn wrap_client_stream<S>(
+    request: &Request,
+    stream: S,
+    connector: Option<Connector>,
+    wait: impl FnMut(&S) -> Result<()>,
+) -> Result<MaybeTlsStream<S>>
+where
+    S: Read + Write,
+{
     #[cfg(any(feature = "native-tls", feature = "__rustls-tls"))]
     let domain = match request.uri().host() {
         Some(d) => Ok(d.to_string()),
@@ -211,11 +360,11 @@ where
 
     let mode = uri_mode(request.uri())?;
 
-    let stream = match connector {
+    match connector {
         Some(conn) => match conn {
             #[cfg(feature = "native-tls")]
             Connector::NativeTls(conn) => {
-                self::encryption::native_tls::wrap_stream(stream, &domain, mode, Some(conn))
+                self::encryption::native_tls::wrap_stream(stream, &domain, mode, Some(conn), wait)
             }
             #[cfg(feature = "__rustls-tls")]
             Connector::Rustls(conn) => {
@@ -226,7 +375,7 @@ where
         None => {
             #[cfg(feature = "native-tls")]
             {
-                self::encryption::native_tls::wrap_stream(stream, &domain, mode, None)
+                self::encryption::native_tls::wrap_stream(stream, &domain, mode, None, wait)
             }
             #[cfg(all(feature = "__rustls-tls", not(feature = "native-tls")))]
             {
@@ -237,7 +386,31 @@ where
                 self::encryption::plain::wrap_stream(stream, mode)
             }
         }
-    }?;
+    }
+}
 
-    client_with_config(request, stream, config)
+/// Drive the TLS handshake of a stream to completion.
+///
+/// `native-tls` does so when wrapping the stream already, while `rustls` would do it lazily
+/// on the first read or write. If the handshake would block, `wait` is called before trying
+/// again.
+#[cfg_attr(not(feature = "__rustls-tls"), allow(unused_variables, unused_mut))]
+pub(crate) fn complete_tls_handshake<S>(
+    stream: &mut MaybeTlsStream<S>,
+    mut wait: impl FnMut(&S) -> Result<()>,
+) -> Result<()>
+where
+    S: Read + Write,
+{
+    #[cfg(feature = "__rustls-tls")]
+    if let MaybeTlsStream::Rustls(s) = stream {
+        while s.conn.is_handshaking() {
+            match s.conn.complete_io(&mut s.sock) {
+                Ok(_) => {}
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => wait(&s.sock)?,
+                Err(e) => return Err(e.into()),
+            }
+        }
+    }
+    Ok(())
 }
@@ -0,0 +1,42 @@
+//! Verifies that the client connection can be bound to a given local address.
+
+#![cfg(all(feature = "handshake", feature = "socket2"))]
+
+use std::{
+    net::{SocketAddr, TcpListener},
+    thread::spawn,
+};
+
+use tungstenite::{
+    accept_with_addr,
+    client::{connect_with_options, ConnectOptions},
+    Message,
+};
+
+#[test]
+fn test_connect_with_local_addr() {
+    // Ask the OS for a free port to bind the client to.
+    let local_addr: SocketAddr = {
+        let probe = TcpListener::bind("127.0.0.1:0").unwrap();
+        probe.local_addr().unwrap()
+    };
+
+    let server = TcpListener::bind("127.0.0.1:3018").unwrap();
+
+    let client_thread = spawn(move || {
+        let options = ConnectOptions::default().local_addr(Some(local_addr));
+        let (mut client, _, _) =
+            connect_with_options("ws://127.0.0.1:3018/socket", options).unwrap();
+        client.send(Message::Text("Hello WebSocket".into())).unwrap();
+    });
+
+    let (stream, peer_addr) = server.accept().unwrap();
+    assert_eq!(peer_addr, local_addr);
+
+    let (mut client_handler, client_addr) = accept_with_addr(stream, None).unwrap();
+    assert_eq!(client_addr, local_addr);
+    let message = client_handler.read().unwrap();
+    assert_eq!(message.into_data(), b"Hello WebSocket"[..]);
+
+    client_thread.join().unwrap();
+}
@@ -0,0 +1,43 @@
+//! Verifies that the redirects followed when connecting are reported.
+
+#![cfg(feature = "handshake")]
+
+use std::{
+    io::{Read, Write},
+    net::TcpListener,
+    thread::spawn,
+};
+
+use http::StatusCode;
+use tungstenite::{
+    accept,
+    client::{connect_with_options, ConnectOptions},
+};
+
+#[test]
+fn test_connect_with_redirects() {
+    let server = TcpListener::bind("127.0.0.1:3021").unwrap();
+
+    let server_thread = spawn(move || {
+        let (mut stream, _) = server.accept().unwrap();
+        let mut buf = [0; 1024];
+        let _ = stream.read(&mut buf).unwrap();
+        stream
+            .write_all(b"HTTP/1.1 302 Found\r\nLocation: ws://127.0.0.1:3021/moved\r\n\r\n")
+            .unwrap();
+        drop(stream);
+
+        let (stream, _) = server.accept().unwrap();
+        let mut socket = accept(stream).unwrap();
+        socket.close(None).unwrap();
+    });
+
+    let (_, response, info) =
+        connect_with_options("ws://127.0.0.1:3021/socket", ConnectOptions::default()).unwrap();
+    assert_eq!(response.status(), StatusCode::SWITCHING_PROTOCOLS);
+    assert_eq!(info.redirects.len(), 1);
+    assert_eq!(info.redirects[0].0, "ws://127.0.0.1:3021/socket");
+    assert_eq!(info.redirects[0].1, StatusCode::FOUND);
+
+    server_thread.join().unwrap();
+}
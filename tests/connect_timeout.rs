@@ -0,0 +1,84 @@
+//! Verifies that the client gives up connecting once the timeout has elapsed.
+
+#![cfg(feature = "handshake")]
+
+use std::{
+    io::{self, Read, Write},
+    net::TcpListener,
+    thread::{sleep, spawn},
+    time::{Duration, Instant},
+};
+
+use tungstenite::{
+    accept,
+    client::{connect_with_options, ConnectOptions},
+    Error, Message,
+};
+
+#[test]
+fn test_connect_with_timeout() {
+    let server = TcpListener::bind("127.0.0.1:3026").unwrap();
+
+    let client_thread = spawn(move || {
+        let options = ConnectOptions::default().timeout(Some(Duration::from_secs(5)));
+        let (mut client, _, _) =
+            connect_with_options("ws://127.0.0.1:3026/socket", options).unwrap();
+        assert_eq!(client.get_ref().tcp_ref().read_timeout().unwrap(), None);
+        client.send(Message::Text("Hello WebSocket".into())).unwrap();
+    });
+
+    let (stream, _) = server.accept().unwrap();
+    let mut client_handler = accept(stream).unwrap();
+    let message = client_handler.read().unwrap();
+    assert_eq!(message.into_data(), b"Hello WebSocket"[..]);
+
+    client_thread.join().unwrap();
+}
+
+#[test]
+fn test_connect_timeout_elapses() {
+    let server = TcpListener::bind("127.0.0.1:3027").unwrap();
+
+    let client_thread = spawn(move || {
+        let start = Instant::now();
+        let options = ConnectOptions::default().timeout(Some(Duration::from_millis(200)));
+        let result = connect_with_options("ws://127.0.0.1:3027/socket", options);
+        match result {
+            Err(Error::Io(e)) => assert_eq!(e.kind(), io::ErrorKind::TimedOut),
+            other => panic!("unexpected result: {other:?}"),
+        }
+        assert!(start.elapsed() < Duration::from_secs(5));
+    });
+
+    // Accept the connection but never answer the handshake.
+    let (_stream, _) = server.accept().unwrap();
+    client_thread.join().unwrap();
+}
+
+#[test]
+fn test_connect_timeout_slow_server() {
+    let server = TcpListener::bind("127.0.0.1:3028").unwrap();
+
+    let client_thread = spawn(move || {
+        let start = Instant::now();
+        let options = ConnectOptions::default().timeout(Some(Duration::from_millis(300)));
+        let result = connect_with_options("ws://127.0.0.1:3028/socket", options);
+        match result {
+            Err(Error::Io(e)) => assert_eq!(e.kind(), io::ErrorKind::TimedOut),
+            other => panic!("unexpected result: {other:?}"),
+        }
+        assert!(start.elapsed() < Duration::from_secs(2));
+    });
+
+    // Send the response so slowly that every single read completes in time, but the
+    // whole response doesn't.
+    let (mut stream, _) = server.accept().unwrap();
+    assert!(stream.read(&mut [0; 1024]).unwrap() > 0);
+    for byte in b"HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\n" {
+        if stream.write_all(&[*byte]).is_err() {
+            break;
+        }
+        sleep(Duration::from_millis(50));
+    }
+    client_thread.join().unwrap();
+}
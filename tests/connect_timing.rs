@@ -0,0 +1,35 @@
+//! Verifies that the client reports the time spent connecting.
+
+#![cfg(feature = "handshake")]
+
+use std::{net::TcpListener, thread::spawn, time::Duration};
+
+use tungstenite::{
+    accept,
+    client::{connect_with_options, ConnectOptions},
+    stream::Mode,
+    Message,
+};
+
+#[test]
+fn test_connect_with_timing() {
+    let server = TcpListener::bind("127.0.0.1:3025").unwrap();
+
+    let client_thread = spawn(move || {
+        let (mut client, _, info) =
+            connect_with_options("ws://127.0.0.1:3025/socket", ConnectOptions::default()).unwrap();
+        assert_eq!(info.mode, Mode::Plain);
+        let timing = info.timing;
+        assert_eq!(timing.tls, Duration::ZERO);
+        assert!(timing.handshake > Duration::ZERO);
+        assert!(timing.total() >= timing.handshake + timing.tcp_connect);
+        client.send(Message::Text("Hello WebSocket".into())).unwrap();
+    });
+
+    let (stream, _) = server.accept().unwrap();
+    let mut client_handler = accept(stream).unwrap();
+    let message = client_handler.read().unwrap();
+    assert_eq!(message.into_data(), b"Hello WebSocket"[..]);
+
+    client_thread.join().unwrap();
+}
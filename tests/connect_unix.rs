@@ -0,0 +1,28 @@
+//! Verifies that a client can connect over a Unix domain socket.
+
+#![cfg(all(unix, feature = "handshake"))]
+
+use std::{os::unix::net::UnixListener, process, thread::spawn};
+
+use tungstenite::{accept, client::connect_unix, Message};
+
+#[test]
+fn test_connect_unix() {
+    let path = std::env::temp_dir().join(format!("tungstenite-test-{}.sock", process::id()));
+    let _ = std::fs::remove_file(&path);
+    let listener = UnixListener::bind(&path).unwrap();
+
+    let server_thread = spawn(move || {
+        let (stream, _) = listener.accept().unwrap();
+        let mut socket = accept(stream).unwrap();
+        let message = socket.read().unwrap();
+        socket.send(message).unwrap();
+    });
+
+    let (mut socket, _) = connect_unix(&path, "ws://localhost/socket", None).unwrap();
+    socket.send(Message::Text("Hello over Unix socket".into())).unwrap();
+    assert_eq!(socket.read().unwrap(), Message::Text("Hello over Unix socket".into()));
+
+    server_thread.join().unwrap();
+    std::fs::remove_file(&path).unwrap();
+}
@@ -0,0 +1,42 @@
+//! Verifies that two WebSockets can talk to each other over an in-memory duplex stream.
+
+#![cfg(feature = "testing")]
+
+use std::io::{self, Read, Write};
+
+use tungstenite::{
+    stream::{duplex, duplex_websockets},
+    Error, Message,
+};
+
+#[test]
+fn duplex_stream() {
+    let (mut a, mut b) = duplex();
+
+    let mut buf = [0; 16];
+    assert_eq!(a.read(&mut buf).unwrap_err().kind(), io::ErrorKind::WouldBlock);
+
+    a.write_all(b"hello").unwrap();
+    assert_eq!(b.read(&mut buf).unwrap(), 5);
+    assert_eq!(&buf[..5], b"hello");
+
+    b.write_all(b"bye").unwrap();
+    drop(b);
+    assert_eq!(a.read(&mut buf).unwrap(), 3);
+    assert_eq!(a.read(&mut buf).unwrap(), 0);
+    assert_eq!(a.write(b"x").unwrap_err().kind(), io::ErrorKind::BrokenPipe);
+}
+
+#[test]
+fn websockets_over_duplex() {
+    let (mut client, mut server) = duplex_websockets(None);
+
+    client.send(Message::Text("Hello WebSocket".into())).unwrap();
+    assert_eq!(server.read().unwrap(), Message::Text("Hello WebSocket".into()));
+
+    server.close(None).unwrap();
+    assert!(client.read().unwrap().is_close());
+    client.flush().unwrap();
+    assert!(server.read().unwrap().is_close());
+    assert!(matches!(server.read(), Err(Error::ConnectionClosed)));
+}
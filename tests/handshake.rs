@@ -5,12 +5,15 @@ use std::{
     time::Duration,
 };
 use tungstenite::{
-    accept_hdr, connect,
+    accept_hdr,
+    client::{connect_with_config, ClientRequestBuilder},
+    connect,
     error::{Error, ProtocolError, SubProtocolError},
     handshake::{
         client::generate_key,
         server::{Request, Response},
     },
+    protocol::WebSocketConfig,
 };
 
 fn create_http_request(uri: &str, subprotocols: Option<Vec<String>>) -> http::Request<()> {
@@ -161,3 +164,46 @@ fn test_request_single_subprotocol() {
         "my-sub-protocol".parse::<http::HeaderValue>().unwrap()
     );
 }
+
+#[test]
+fn test_stored_handshake_response() {
+    server_thread(3019, Some(vec!["my-sub-protocol".to_string()]));
+    sleep(Duration::from_secs(1));
+
+    let (socket, _) = connect(create_http_request(
+        "ws://127.0.0.1:3019",
+        Some(vec!["my-sub-protocol".to_string()]),
+    ))
+    .unwrap();
+    assert!(socket.handshake_response().is_none());
+
+    server_thread(3020, Some(vec!["my-sub-protocol".to_string()]));
+    sleep(Duration::from_secs(1));
+
+    let config = WebSocketConfig::default().store_handshake_response(true);
+    let (socket, response) = connect_with_config(
+        create_http_request("ws://127.0.0.1:3020", Some(vec!["my-sub-protocol".to_string()])),
+        Some(config),
+        3,
+    )
+    .unwrap();
+    let stored = socket.handshake_response().unwrap();
+    assert_eq!(stored.status(), response.status());
+    assert_eq!(stored.headers(), response.headers());
+}
+
+#[test]
+fn test_selected_subprotocol() {
+    for (port, selected) in [(3022, "chat"), (3023, "superchat"), (3024, "json")] {
+        server_thread(port, Some(vec![selected.to_string()]));
+        sleep(Duration::from_secs(1));
+
+        let uri = format!("ws://127.0.0.1:{port}").parse().unwrap();
+        let request = ClientRequestBuilder::new(uri)
+            .with_sub_protocol("chat")
+            .with_sub_protocol("superchat")
+            .with_sub_protocol("json");
+        let (socket, _) = connect(request).unwrap();
+        assert_eq!(socket.selected_subprotocol(), Some(selected));
+    }
+}
@@ -48,7 +48,9 @@ fn test_no_send_after_close() {
     assert!(err.is_err());
 
     match err.unwrap_err() {
-        Error::Protocol(s) => assert_eq!(s, ProtocolError::SendAfterClosing),
+        Error::Protocol(s) => {
+            assert_eq!(s, ProtocolError::SendAfterClosing(Message::Text("Hello WebSocket".into())))
+        }
         e => panic!("unexpected error: {:?}", e),
     }
 